@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::Result;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::CONFIG;
+use crate::lease::{create_lease, release_lease};
+use crate::proxy::{Anonymity, Proxy, ProxyType};
+use crate::selector::ProxyFilter;
+use crate::reputation::report;
+use crate::session::{rotate_session, session_exists, session_proxy};
+
+#[derive(Serialize)]
+struct RotateResponse {
+    session: String,
+    proxy: Proxy,
+}
+
+#[derive(Serialize)]
+struct FeedbackResponse {
+    proxy: String,
+    domain: String,
+    bad: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Error caused by the request itself, answered with its own status.
+/// Any other error means no proxy is available right now and is answered with 503, so callers know to retry.
+#[derive(Debug)]
+struct RequestError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+fn bad_request(message: impl fmt::Display) -> anyhow::Error {
+    anyhow::Error::new(RequestError { status: StatusCode::BAD_REQUEST, message: message.to_string() })
+}
+
+fn not_found(message: impl fmt::Display) -> anyhow::Error {
+    anyhow::Error::new(RequestError { status: StatusCode::NOT_FOUND, message: message.to_string() })
+}
+
+pub async fn init_api_server() -> Result<()> {
+    info!("Initializing api server");
+    let api_port = CONFIG.lock().unwrap().as_ref().unwrap().api_port;
+    let listen_addr: SocketAddr = format!("127.0.0.1:{}", api_port).parse()?;
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(handle_request))
+    });
+    let server = Server::try_bind(&listen_addr)?.serve(make_service);
+    info!("Api server listening at {}", listen_addr);
+    server.await?;
+    Ok(())
+}
+
+async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let segments: Vec<String> = request.uri().path().split('/').filter(|segment| !segment.is_empty()).map(String::from).collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let query = query_params(&request);
+    let result = match (request.method(), segments.as_slice()) {
+        // POST /sessions/{id}/rotate?blacklist=true
+        (&Method::POST, ["sessions", session_id, "rotate"]) => {
+            let blacklist = query.get("blacklist").is_some_and(|value| value == "true");
+            if !session_exists(session_id) {
+                Err(not_found(format!("Session {} not found", session_id)))
+            } else {
+                rotate_session(session_id, blacklist).map(|proxy| json_response(StatusCode::OK, &RotateResponse {
+                    session: session_id.to_string(),
+                    proxy,
+                }))
+            }
+        }
+        // POST /leases?country=US,DE&type=SOCKS5&max_latency_ms=2000&min_score=50&anonymity=elite&asn=AS7922&exclude_datacenter=true&ttl=600
+        (&Method::POST, ["leases"]) => {
+            lease_filter(&query).and_then(|filter| {
+                let ttl = query.get("ttl").map(|ttl| ttl.parse::<u64>()).transpose().map_err(|err| bad_request(format!("Invalid ttl, {}", err)))?;
+                create_lease(&filter, ttl)
+            }).map(|lease| json_response(StatusCode::OK, &lease))
+        }
+        // DELETE /leases/{id}?bad=true
+        (&Method::DELETE, ["leases", lease_id]) => {
+            let bad = query.get("bad").is_some_and(|value| value == "true");
+            release_lease(lease_id, bad)
+                .ok_or_else(|| not_found(format!("Lease {} not found", lease_id)))
+                .map(|lease| json_response(StatusCode::OK, &lease))
+        }
+        // POST /feedback?proxy=1.2.3.4:8080&domain=example.com&bad=true, or session=id instead of proxy
+        (&Method::POST, ["feedback"]) => {
+            submit_feedback(&query).map(|feedback| json_response(StatusCode::OK, &feedback))
+        }
+        _ => Ok(json_response(StatusCode::NOT_FOUND, &ErrorResponse { error: String::from("Not found") })),
+    };
+    Ok(result.unwrap_or_else(|err| {
+        error!("Api request {} {} failed, {:#}", request.method(), request.uri(), err);
+        let status = match err.downcast_ref::<RequestError>() {
+            Some(request_error) => request_error.status,
+            None => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        json_response(status, &ErrorResponse { error: format!("{:#}", err) })
+    }))
+}
+
+fn submit_feedback(query: &HashMap<String, String>) -> Result<FeedbackResponse> {
+    let domain = query.get("domain").ok_or_else(|| bad_request("Missing domain"))?;
+    let proxy = match (query.get("proxy"), query.get("session")) {
+        (Some(proxy), _) => proxy.clone(),
+        (None, Some(session_id)) => session_proxy(session_id).ok_or_else(|| not_found(format!("Session {} has no proxy", session_id)))?,
+        (None, None) => return Err(bad_request("Missing proxy or session")),
+    };
+    let bad = query.get("bad").map(String::as_str) != Some("false");
+    report(&proxy, domain, bad);
+    Ok(FeedbackResponse { proxy, domain: domain.clone(), bad })
+}
+
+fn lease_filter(query: &HashMap<String, String>) -> Result<ProxyFilter> {
+    let mut filter = ProxyFilter::default();
+    if let Some(countries) = query.get("country") {
+        filter.countries = countries.split(',').map(String::from).collect();
+    }
+    if let Some(proxy_types) = query.get("type") {
+        filter.proxy_types = proxy_types.split(',').map(ProxyType::from_str).collect::<Result<_>>().map_err(bad_request)?;
+    }
+    if let Some(max_latency_ms) = query.get("max_latency_ms") {
+        filter.max_latency_ms = Some(max_latency_ms.parse().map_err(|err| bad_request(format!("Invalid max_latency_ms, {}", err)))?);
+    }
+    if let Some(min_score) = query.get("min_score") {
+        filter.min_score = Some(min_score.parse().map_err(|err| bad_request(format!("Invalid min_score, {}", err)))?);
+    }
+    if let Some(asns) = query.get("asn") {
+        filter.asns = asns.split(',').map(|asn| asn.trim_start_matches("AS").parse()).collect::<Result<_, _>>().map_err(|err| bad_request(format!("Invalid asn, {}", err)))?;
+    }
+    filter.exclude_datacenter = query.get("exclude_datacenter").is_some_and(|value| value == "true");
+    filter.udp_associate = query.get("udp").is_some_and(|value| value == "true");
+    filter.http_get = query.get("http_get").is_some_and(|value| value == "true");
+    if let Some(min_anonymity) = query.get("anonymity") {
+        filter.min_anonymity = Some(Anonymity::from_str(min_anonymity).map_err(bad_request)?);
+    }
+    Ok(filter)
+}
+
+fn query_params(request: &Request<Body>) -> HashMap<String, String> {
+    form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    match serde_json::to_string(body) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(err) => {
+            error!("Api response serialize failed, {}", err);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::CONFIG;
+
+// errno values of the per process and system wide open file limits, the same on linux and the BSDs
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+
+struct CheckLimiterState {
+    limit: usize,
+    /// Permits to forget instead of releasing, so the limit drops without waiting for running checks
+    pending_shrink: usize,
+    /// Checks finished since the limit last changed
+    finished: usize,
+}
+
+lazy_static! {
+    // Shared by the pool checker and provider validation, filled with `check_concurrency` permits by `init_check_limiter`
+    static ref CHECK_SEMAPHORE: Arc<Semaphore> = Arc::new(Semaphore::new(0));
+    static ref CHECK_LIMITER: Arc<Mutex<CheckLimiterState>> = Arc::new(Mutex::new(CheckLimiterState {
+        limit: 0,
+        pending_shrink: 0,
+        finished: 0,
+    }));
+}
+
+/// Held for the duration of a single proxy check
+pub struct CheckPermit {
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for CheckPermit {
+    fn drop(&mut self) {
+        let mut state = CHECK_LIMITER.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            if state.pending_shrink > 0 {
+                state.pending_shrink -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl CheckPermit {
+    /// Feed the outcome of the check to the adaptive limit.
+    /// Errors caused by this host, like running out of file descriptors or failing DNS lookups, halve the limit,
+    /// while every `limit` checks finished without one raise it by one up to `check_max_concurrency`.
+    pub fn report(&self, error: Option<&anyhow::Error>) {
+        let (adaptive, max_concurrency) = {
+            let config = CONFIG.lock().unwrap();
+            let config = config.as_ref().unwrap();
+            (config.check_adaptive_concurrency, config.check_max_concurrency)
+        };
+        if !adaptive {
+            return;
+        }
+        let mut state = CHECK_LIMITER.lock().unwrap();
+        if error.is_some_and(is_local_error) {
+            let target = (state.limit / 2).max(1);
+            if target < state.limit {
+                state.pending_shrink += state.limit - target;
+                warn!("Local error while checking, lowered check concurrency from {} to {}", state.limit, target);
+                state.limit = target;
+            }
+            state.finished = 0;
+            return;
+        }
+        state.finished += 1;
+        if state.finished >= state.limit && state.limit < max_concurrency {
+            state.finished = 0;
+            state.limit += 1;
+            if state.pending_shrink > 0 {
+                state.pending_shrink -= 1;
+            } else {
+                CHECK_SEMAPHORE.add_permits(1);
+            }
+            info!("Raised check concurrency to {}", state.limit);
+        }
+    }
+}
+
+pub fn init_check_limiter() {
+    let check_concurrency = CONFIG.lock().unwrap().as_ref().unwrap().check_concurrency.max(1);
+    CHECK_LIMITER.lock().unwrap().limit = check_concurrency;
+    CHECK_SEMAPHORE.add_permits(check_concurrency);
+}
+
+/// Wait until one more proxy check may run
+pub async fn acquire_check_permit() -> CheckPermit {
+    let permit = Arc::clone(&CHECK_SEMAPHORE).acquire_owned().await.unwrap();
+    CheckPermit { permit: Some(permit) }
+}
+
+/// Whether the error comes from this host rather than from the proxy or the check target
+fn is_local_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+            if matches!(io_error.raw_os_error(), Some(ENFILE) | Some(EMFILE))
+                || io_error.kind() == io::ErrorKind::AddrNotAvailable {
+                return true;
+            }
+        }
+        let message = cause.to_string();
+        message.contains("dns error") || message.contains("failed to lookup address")
+    })
+}
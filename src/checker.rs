@@ -1,47 +1,195 @@
-use std::collections::BTreeSet;
-use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use fast_socks5::util::target_addr::TargetAddr;
+use lazy_static::lazy_static;
 use log::info;
-use tokio::sync::Semaphore;
+use sha2::{Digest, Sha256};
+use tokio::time::{Instant, timeout};
 
 use crate::{CONFIG, PROXY_POOL};
-use crate::proxy::{Proxy, ProxyType};
+use crate::check_limiter::acquire_check_permit;
+use crate::config::{CheckPolicy, CheckTarget, Config};
+use crate::geoip::enrich;
+use crate::judge::classify_anonymity;
+use crate::prober::{probe_capabilities, probe_protocols};
+use crate::proxy::{Anonymity, LatencySample, Proxy, ProxyType, update_pool_proxy};
+use crate::socks::tunnel;
 use crate::time::current_timestamp;
 
-pub async fn check_proxy_pool() -> Result<()> {
-    let proxy_pool: BTreeSet<Proxy>;
-    proxy_pool = Arc::clone(&PROXY_POOL).lock().unwrap().iter().map(|proxy| (*proxy).clone()).collect();
-    let mut tasks = Vec::new();
-    let semaphore = Arc::new(Semaphore::new(10));
-    for proxy in proxy_pool.iter() {
-        let mut proxy = (*proxy).clone();
+lazy_static! {
+    // Addresses of proxies with a check running, so a slow check is not scheduled again by the next tick
+    static ref CHECKS_IN_FLIGHT: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+}
+
+static CHECKS_FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Removes the proxy from the in flight checks when its check task ends, however it ends
+struct InFlightCheck {
+    address: String,
+}
+
+impl Drop for InFlightCheck {
+    fn drop(&mut self) {
+        CHECKS_IN_FLIGHT.lock().unwrap().remove(&self.address);
+        CHECKS_FINISHED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Start checks of the proxies whose next check is due and which have no check running, without waiting for them.
+/// Returns how many checks finished since the previous call.
+/// Proxies which were never scheduled, new or loaded from an older pool file, get a check time spread over `check_interval`.
+pub fn check_proxy_pool() -> usize {
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let now = current_timestamp();
+    let due_proxies: Vec<Proxy> = {
+        let mut proxy_pool = PROXY_POOL.lock().unwrap();
+        let unscheduled: Vec<Proxy> = proxy_pool.iter().filter(|proxy| proxy.next_check == 0).cloned().collect();
+        for proxy in unscheduled.iter() {
+            update_pool_proxy(&mut proxy_pool, proxy, |proxy| {
+                proxy.next_check = now + jitter(proxy, config.check_interval);
+            });
+        }
+        let mut in_flight = CHECKS_IN_FLIGHT.lock().unwrap();
+        proxy_pool
+            .iter()
+            .filter(|proxy| proxy.next_check <= now && in_flight.insert(proxy.address()))
+            .cloned()
+            .collect()
+    };
+    for proxy in due_proxies {
+        let config = config.clone();
         let proxy_pool = Arc::clone(&PROXY_POOL);
-        let semaphore = Arc::clone(&semaphore);
-        tasks.push(tokio::spawn(async move {
-            let _ = semaphore.acquire().await.unwrap();
-            let ip = check_proxy(&proxy).await;
-            match ip {
-                Ok(_) => {
-                    proxy.last_checked = current_timestamp();
-                    proxy_pool.lock().unwrap().insert(proxy.clone());
-                    info!("Updated proxy {}:{}", proxy.proxy_ip, proxy.proxy_port);
+        tokio::spawn(async move {
+            let _in_flight = InFlightCheck { address: proxy.address() };
+            // Probe again after a failure, the proxy may have switched protocols
+            let mut proxy = proxy;
+            let mut probed = None;
+            if proxy.protocols.is_empty() || proxy.health.is_degraded() {
+                let protocols = probe_protocols(&proxy).await;
+                if !protocols.is_empty() {
+                    proxy.set_protocols(protocols.clone());
+                    probed = Some(protocols);
+                }
+            }
+            let result = check_proxy(&proxy).await;
+            match result {
+                Ok(result) => {
+                    // Capabilities depend on the relay protocol, so they are probed again when it may have changed
+                    let capabilities = if probed.is_some() || proxy.capabilities.last_probed == 0 {
+                        Some(probe_capabilities(&proxy).await)
+                    } else {
+                        None
+                    };
+                    update_pool_proxy(&mut proxy_pool.lock().unwrap(), &proxy, |proxy| {
+                        proxy.last_checked = current_timestamp();
+                        proxy.latency.record(result.latency);
+                        if result.anonymity.is_some() {
+                            proxy.anonymity = result.anonymity;
+                        }
+                        if let Some(exit_ip) = result.exit_ip {
+                            match &proxy.exit_ip {
+                                Some(old_exit_ip) if *old_exit_ip != exit_ip => {
+                                    info!("Proxy {} exit ip changed from {} to {}", proxy.address(), old_exit_ip, exit_ip);
+                                }
+                                _ => {}
+                            }
+                            proxy.exit_ip = Some(exit_ip);
+                        }
+                        enrich(proxy);
+                        if let Some(protocols) = probed {
+                            proxy.set_protocols(protocols);
+                        }
+                        if let Some(capabilities) = capabilities {
+                            proxy.capabilities = capabilities;
+                        }
+                        proxy.health.record(true, config.check_success_window);
+                        proxy.next_check = next_check_time(proxy, &config, current_timestamp());
+                    });
+                    info!("Updated proxy {}:{}, {}ms", proxy.proxy_ip, proxy.proxy_port, result.latency.total_ms);
                 }
-                Err(_) => {
-                    proxy_pool.lock().unwrap().remove(&proxy);
-                    info!("Removed proxy {}:{}", proxy.proxy_ip, proxy.proxy_port);
+                Err(error) => {
+                    let mut proxy_pool = proxy_pool.lock().unwrap();
+                    let mut health = proxy.health.clone();
+                    update_pool_proxy(&mut proxy_pool, &proxy, |proxy| {
+                        proxy.health.record(false, config.check_success_window);
+                        proxy.next_check = next_check_time(proxy, &config, current_timestamp());
+                        health = proxy.health.clone();
+                    });
+                    let window_full = health.recent.len() >= config.check_success_window;
+                    if health.consecutive_failures >= config.check_max_consecutive_failures
+                        || (window_full && health.success_rate() < config.check_min_success_rate) {
+                        proxy_pool.retain(|entry| *entry != proxy);
+                        info!("Removed proxy {}:{}, {} consecutive failures, {:.0}% success rate",
+                            proxy.proxy_ip, proxy.proxy_port, health.consecutive_failures, health.success_rate() * 100.0);
+                    } else {
+                        info!("Proxy {}:{} degraded, {} consecutive failures, {:#}",
+                            proxy.proxy_ip, proxy.proxy_port, health.consecutive_failures, error);
+                    }
                 }
             }
-        }));
+        });
     }
-    for task in tasks {
-        task.await?;
+    CHECKS_FINISHED.swap(0, Ordering::Relaxed)
+}
+
+/// When to check the proxy next, based on its check history.
+/// Failing proxies back off exponentially from `check_min_interval`, new and flaky ones are checked every half
+/// `check_interval`, and stable ones wait longer the more checks in a row they passed, up to `check_max_interval`.
+fn next_check_time(proxy: &Proxy, config: &Config, now: u64) -> u64 {
+    let health = &proxy.health;
+    let interval = if health.consecutive_failures > 0 {
+        config.check_min_interval.saturating_mul(1 << (health.consecutive_failures - 1).min(16))
+    } else if health.recent.len() < config.check_success_window || health.success_rate() < 1.0 {
+        config.check_interval / 2
+    } else {
+        let window = config.check_success_window.max(1) as u64;
+        config.check_interval + config.check_interval * health.consecutive_successes as u64 / window
+    };
+    let interval = interval.clamp(config.check_min_interval, config.check_max_interval.max(config.check_min_interval));
+    now + interval + jitter(proxy, interval / 10)
+}
+
+/// Stable per proxy offset below `range`, keeps proxies scheduled at the same time from being checked in one burst
+fn jitter(proxy: &Proxy, range: u64) -> u64 {
+    if range == 0 {
+        return 0;
     }
-    Ok(())
+    let mut hasher = DefaultHasher::new();
+    proxy.address().hash(&mut hasher);
+    hasher.finish() % range
+}
+
+pub struct CheckResult {
+    /// Timings of the first passing check target
+    pub latency: LatencySample,
+    /// Unset if no judge is configured or the judge request failed
+    pub anonymity: Option<Anonymity>,
+    /// Source address seen by the judge, or else by the first passing target marked `reports_exit_ip`
+    pub exit_ip: Option<String>,
 }
 
-pub async fn check_proxy(proxy: &Proxy) -> Result<String> {
+/// Check the proxy against the configured check targets and policy, waiting for a slot of the shared check concurrency
+pub async fn check_proxy(proxy: &Proxy) -> Result<CheckResult> {
+    // Checks go through the relay protocol, others like SOCKS4 can't be checked nor used
+    if !proxy.is_relayable() {
+        return Err(anyhow!("Proxy {} speaks no protocol the relay supports, {:?}", proxy.address(), proxy.protocols));
+    }
+    let permit = acquire_check_permit().await;
+    let result = run_check(proxy).await;
+    permit.report(result.as_ref().err());
+    result
+}
+
+async fn run_check(proxy: &Proxy) -> Result<CheckResult> {
     let mut proxy_scheme = String::new();
     match proxy.proxy_type {
         ProxyType::HTTP => {
@@ -60,14 +208,148 @@ pub async fn check_proxy(proxy: &Proxy) -> Result<String> {
     proxy_scheme += proxy.proxy_ip.as_str();
     proxy_scheme += ":";
     proxy_scheme += proxy.proxy_port.to_string().as_str();
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
     let client = reqwest::ClientBuilder::new().proxy(
         reqwest::Proxy::all(proxy_scheme.as_str())?
-    ).connect_timeout(Duration::from_secs(CONFIG.lock().unwrap().as_ref().unwrap().check_timeout))
+    ).connect_timeout(Duration::from_secs(config.check_timeout))
+        .timeout(Duration::from_secs(config.check_timeout))
         .build()?;
-    // https://api.ip.sb/ip
-    // https://myip.ipip.net/s
-    let request = client.get("https://myip.ipip.net/s").build()?;
+
+    // Targets run in order, stopping as soon as the policy is decided
+    let required = match config.check_policy {
+        CheckPolicy::Any => 1,
+        CheckPolicy::All => config.check_targets.len(),
+        CheckPolicy::Quorum => config.check_quorum.clamp(1, config.check_targets.len().max(1)),
+    };
+    let mut passed = 0;
+    let mut failed = 0;
+    let mut first_result: Option<CheckResult> = None;
+    let mut exit_ip = None;
+    let mut last_error = anyhow!("No check target configured");
+    for target in config.check_targets.iter() {
+        match check_target(&client, target).await {
+            Ok((body, first_byte_ms, total_ms)) => {
+                passed += 1;
+                if target.reports_exit_ip && exit_ip.is_none() {
+                    exit_ip = parse_exit_ip(&body);
+                }
+                if first_result.is_none() {
+                    let connect_ms = measure_connect(proxy, &target.url, Duration::from_secs(config.check_timeout))
+                        .await
+                        .with_context(|| format!("Tunnel to {} failed", target.url))?;
+                    first_result = Some(CheckResult {
+                        exit_ip: None,
+                        latency: LatencySample { connect_ms, first_byte_ms, total_ms },
+                        anonymity: None,
+                    });
+                }
+            }
+            Err(error) => {
+                failed += 1;
+                last_error = error;
+            }
+        }
+        if passed >= required || config.check_targets.len() - failed < required {
+            break;
+        }
+    }
+    let mut result = match first_result {
+        Some(result) if passed >= required => result,
+        _ => return Err(last_error.context(format!("{} of {} required check targets passed", passed, required))),
+    };
+    result.exit_ip = exit_ip;
+
+    // A failed judge request leaves the proxy unclassified instead of failing the check
+    if let Some(judge_url) = &config.judge_url {
+        match classify_anonymity(&client, judge_url).await {
+            Ok((anonymity, judge_exit_ip)) => {
+                result.anonymity = Some(anonymity);
+                if let Some(judge_exit_ip) = judge_exit_ip {
+                    result.exit_ip = Some(judge_exit_ip.to_string());
+                }
+            }
+            Err(error) => info!("Classify proxy {} failed, {:#}", proxy.address(), error),
+        }
+    }
+    Ok(result)
+}
+
+/// The exit ip of an ip echo body, which has to be a single address and nothing else but surrounding whitespace
+fn parse_exit_ip(body: &str) -> Option<String> {
+    IpAddr::from_str(body.trim()).ok().map(|ip| ip.to_string())
+}
+
+/// Time a tunnel through the proxy to the host of the check target, made the way the relay connects to targets.
+/// reqwest doesn't expose its connection timings, so this is the connect time recorded for the check.
+async fn measure_connect(proxy: &Proxy, url: &str, connect_timeout: Duration) -> Result<u64> {
+    let url = reqwest::Url::parse(url)?;
+    let host = url.host_str().ok_or_else(|| anyhow!("Check target {} has no host", url))?;
+    let port = url.port_or_known_default().ok_or_else(|| anyhow!("Check target {} has no port", url))?;
+    let target = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => TargetAddr::Domain(host.to_string(), port),
+    };
+    let start = Instant::now();
+    timeout(connect_timeout, tunnel(proxy, &target)).await??;
+    Ok(start.elapsed().as_millis() as u64)
+}
+
+/// Request a check target through the proxy, returns the body with the time to first byte and total time in milliseconds
+/// if the response matches every expectation of the target
+async fn check_target(client: &reqwest::Client, target: &CheckTarget) -> Result<(String, u64, u64)> {
+    let request = client.request(target.http_method.clone(), target.url.as_str()).build()?;
+    let start = Instant::now();
     let response = client.execute(request).await?;
-    let ip = response.text().await?;
-    Ok(ip)
-}
\ No newline at end of file
+    let first_byte_ms = start.elapsed().as_millis() as u64;
+    let status = response.status().as_u16();
+    if let Some(expected_status) = target.expected_status {
+        if status != expected_status {
+            return Err(anyhow!("{} returned status {}, expected {}", target.url, status, expected_status));
+        }
+    }
+    let body = response.bytes().await?;
+    let total_ms = start.elapsed().as_millis() as u64;
+    if let Some(expected_sha256) = &target.expected_body_sha256 {
+        let digest: String = Sha256::digest(&body).iter().map(|byte| format!("{:02x}", byte)).collect();
+        if !digest.eq_ignore_ascii_case(expected_sha256) {
+            return Err(anyhow!("{} returned body with sha256 {}", target.url, digest));
+        }
+    }
+    let body = String::from_utf8_lossy(&body).to_string();
+    if let Some(expected_body) = &target.expected_body {
+        if !body.contains(expected_body.as_str()) {
+            return Err(anyhow!("{} returned body without {:?}", target.url, expected_body));
+        }
+    }
+    if let Some(body_regex) = &target.body_regex {
+        if !body_regex.is_match(&body) {
+            return Err(anyhow!("{} returned body not matching {:?}", target.url, body_regex.as_str()));
+        }
+    }
+    Ok((body, first_byte_ms, total_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_exit_ip_echoes() {
+        let cases = [
+            ("203.0.113.7", Some("203.0.113.7")),
+            ("203.0.113.7\n", Some("203.0.113.7")),
+            ("  2001:db8::1\r\n", Some("2001:db8::1")),
+            ("2001:DB8:0:0:0:0:0:1", Some("2001:db8::1")),
+            ("", None),
+            ("a::before{}", None),
+            ("version 1.0.0.1 of lib", None),
+            ("203.0.113.7 203.0.113.8", None),
+            ("<html>203.0.113.7</html>", None),
+            ("203.0.113.7.", None),
+            ("203.0.113.7:8080", None),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(parse_exit_ip(body).as_deref(), expected, "{:?}", body);
+        }
+    }
+}
@@ -2,20 +2,179 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use log::{error, info};
+use regex::Regex;
+use reqwest::Method;
 use serde::{Deserialize, Serialize};
 
 use crate::CONFIG;
+use crate::selector::ProxyFilter;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransparentMode {
+    /// iptables / nftables `REDIRECT`, original destination is read back with `SO_ORIGINAL_DST`
+    Redirect,
+    /// iptables / nftables `TPROXY`, the socket is bound with `IP_TRANSPARENT` and keeps the original destination as local address
+    Tproxy,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CheckTarget {
+    pub url: String,
+    #[serde(default = "default_check_method")]
+    pub method: String,
+    /// Any status is accepted when unset
+    #[serde(default)]
+    pub expected_status: Option<u16>,
+    /// Substring the response body must contain
+    #[serde(default)]
+    pub expected_body: Option<String>,
+    #[serde(default)]
+    pub expected_body_regex: Option<String>,
+    /// Hex encoded sha256 digest of the whole response body
+    #[serde(default)]
+    pub expected_body_sha256: Option<String>,
+    /// The target answers with nothing but the address the request came from, which is taken as the exit ip.
+    /// Ignored when a judge is configured, the judge reports the exit ip.
+    #[serde(default)]
+    pub reports_exit_ip: bool,
+    /// Parsed from `method` by `compile`
+    #[serde(skip)]
+    pub http_method: Method,
+    /// Compiled from `expected_body_regex` by `compile`
+    #[serde(skip)]
+    pub body_regex: Option<Regex>,
+}
+
+impl CheckTarget {
+    /// Validate the target and prepare its method and regex, so a typo fails at startup rather than every check
+    pub fn compile(&mut self) -> Result<()> {
+        reqwest::Url::parse(&self.url).with_context(|| format!("Invalid check target url {}", self.url))?;
+        self.http_method = Method::from_bytes(self.method.to_uppercase().as_bytes())
+            .with_context(|| format!("Invalid method {} of check target {}", self.method, self.url))?;
+        self.body_regex = match &self.expected_body_regex {
+            Some(expected_body_regex) => Some(Regex::new(expected_body_regex)
+                .with_context(|| format!("Invalid expected_body_regex of check target {}", self.url))?),
+            None => None,
+        };
+        if let Some(expected_sha256) = &self.expected_body_sha256 {
+            if expected_sha256.len() != 64 || !expected_sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow!("Invalid expected_body_sha256 of check target {}", self.url));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_check_method() -> String {
+    String::from("GET")
+}
+
+/// How many check targets have to pass for a proxy to be healthy
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckPolicy {
+    Any,
+    All,
+    /// At least `check_quorum` targets
+    Quorum,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DomainLimit {
+    /// Target domain, also matches its subdomains
+    pub domain: String,
+    /// Concurrent tunnels to the domain across all clients, 0 for unlimited
+    #[serde(default)]
+    pub max_concurrent: usize,
+    /// New connections to the domain per second across all clients, 0 for unlimited
+    #[serde(default)]
+    pub max_connections_per_second: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoutedListener {
+    pub port: u64,
+    /// Only proxies matching this filter are selected for connections to this port
+    #[serde(flatten)]
+    pub filter: ProxyFilter,
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(default)]
 pub struct Config {
     pub check_timeout: u64,
+    /// Base interval between checks of a proxy
     pub check_interval: u64,
+    /// Bounds of the per proxy check interval
+    pub check_min_interval: u64,
+    pub check_max_interval: u64,
+    /// How often the scheduler looks for proxies due for a check
+    pub check_tick_interval: u64,
+    pub check_targets: Vec<CheckTarget>,
+    pub check_policy: CheckPolicy,
+    pub check_quorum: usize,
+    /// Proxy checks running at once, shared by the pool checker and provider validation
+    pub check_concurrency: usize,
+    /// Lower the check concurrency on local errors like file descriptor exhaustion or DNS failures,
+    /// and raise it again up to `check_max_concurrency` while checks run without them
+    pub check_adaptive_concurrency: bool,
+    pub check_max_concurrency: usize,
+    /// A proxy is removed after this many failed checks in a row
+    pub check_max_consecutive_failures: u32,
+    /// A proxy is removed when less than this share of its latest `check_success_window` checks passed, 0 to disable
+    pub check_min_success_rate: f64,
+    pub check_success_window: usize,
+    pub judge_enabled: bool,
+    pub judge_port: u64,
+    /// Url proxies reach the judge at, the built-in one or an external judge answering in the same format.
+    /// Proxies are not classified when unset.
+    pub judge_url: Option<String>,
     pub update_interval: u64,
+    /// Address the socks listeners bind to, set to a public or 0.0.0.0 address to accept remote clients
+    pub socks_server_bind: String,
     pub socks_server_port: u64,
     pub socks_server_timeout: u64,
+    pub socks_server_listeners: Vec<RoutedListener>,
+    pub socks_server_tls_enabled: bool,
+    pub socks_server_tls_cert: String,
+    pub socks_server_tls_key: String,
+    pub socks_server_tls_client_ca: Option<String>,
+    pub unix_socket_enabled: bool,
+    pub unix_socket_path: String,
+    pub unix_socket_mode: String,
+    pub unix_socket_uid: Option<u32>,
+    pub unix_socket_gid: Option<u32>,
+    pub proxy_protocol_enabled: bool,
+    pub proxy_protocol_trusted_sources: Vec<String>,
+    pub transparent_proxy_enabled: bool,
+    /// IPv4 address the transparent listener binds to, it has to be reachable for the redirected traffic
+    pub transparent_proxy_bind: String,
+    pub transparent_proxy_port: u64,
+    pub transparent_proxy_mode: TransparentMode,
+    pub proxy_affinity_enabled: bool,
+    pub proxy_reuse_interval: u64,
+    pub proxy_max_requests_per_minute: usize,
+    /// Rotate proxies sharing an exit ip as a single proxy
+    pub proxy_dedup_by_exit_ip: bool,
+    pub session_enabled: bool,
+    pub session_ttl: u64,
+    pub api_enabled: bool,
+    pub api_port: u64,
+    pub lease_default_ttl: u64,
+    pub lease_max_ttl: u64,
+    pub reputation_bad_threshold: u32,
+    pub reputation_ttl: u64,
+    pub domain_limits: Vec<DomainLimit>,
+    pub domain_limit_queue_timeout: u64,
+    /// `.mmdb` city database, MaxMind GeoIP2 / GeoLite2 City or DB-IP City Lite
+    pub geoip_city_database: Option<String>,
+    /// `.mmdb` ASN database, MaxMind GeoLite2 ASN or DB-IP ASN Lite
+    pub geoip_asn_database: Option<String>,
+    /// ASNs of hosting and cloud providers, skipped by filters excluding datacenters
+    pub datacenter_asns: Vec<u32>,
     pub provider_docip_enabled: bool,
     pub provider_checkerproxy_enabled: bool,
 }
@@ -25,9 +184,69 @@ impl Config {
         Config {
             check_timeout: 10,
             check_interval: 300,
+            check_min_interval: 60,
+            check_max_interval: 3600,
+            check_tick_interval: 10,
+            check_targets: vec![CheckTarget {
+                url: String::from("https://myip.ipip.net/s"),
+                method: default_check_method(),
+                expected_status: Some(200),
+                expected_body: None,
+                expected_body_regex: None,
+                expected_body_sha256: None,
+                reports_exit_ip: true,
+                http_method: Method::GET,
+                body_regex: None,
+            }],
+            check_policy: CheckPolicy::Any,
+            check_quorum: 1,
+            check_concurrency: 10,
+            check_adaptive_concurrency: false,
+            check_max_concurrency: 100,
+            check_max_consecutive_failures: 3,
+            check_min_success_rate: 0.5,
+            check_success_window: 10,
+            judge_enabled: false,
+            judge_port: 2341,
+            judge_url: None,
             update_interval: 6000,
+            socks_server_bind: String::from("127.0.0.1"),
             socks_server_port: 2333,
             socks_server_timeout: 10,
+            socks_server_listeners: vec![],
+            socks_server_tls_enabled: false,
+            socks_server_tls_cert: String::from("cert.pem"),
+            socks_server_tls_key: String::from("key.pem"),
+            socks_server_tls_client_ca: None,
+            unix_socket_enabled: false,
+            unix_socket_path: String::from("akivili.sock"),
+            unix_socket_mode: String::from("0660"),
+            unix_socket_uid: None,
+            unix_socket_gid: None,
+            proxy_protocol_enabled: false,
+            proxy_protocol_trusted_sources: vec![String::from("127.0.0.1")],
+            transparent_proxy_enabled: false,
+            transparent_proxy_bind: String::from("0.0.0.0"),
+            transparent_proxy_port: 2340,
+            transparent_proxy_mode: TransparentMode::Redirect,
+            proxy_affinity_enabled: false,
+            proxy_reuse_interval: 0,
+            proxy_max_requests_per_minute: 0,
+            proxy_dedup_by_exit_ip: false,
+            session_enabled: false,
+            session_ttl: 1800,
+            api_enabled: false,
+            api_port: 2339,
+            lease_default_ttl: 600,
+            lease_max_ttl: 3600,
+            reputation_bad_threshold: 2,
+            reputation_ttl: 86400,
+            domain_limits: vec![],
+            domain_limit_queue_timeout: 10,
+            geoip_city_database: None,
+            geoip_asn_database: None,
+            // Amazon, Google, Microsoft, DigitalOcean, OVH, Hetzner, Linode, Vultr, Alibaba, Tencent, Oracle
+            datacenter_asns: vec![16509, 14618, 15169, 396982, 8075, 14061, 16276, 24940, 63949, 20473, 45102, 132203, 31898],
             provider_docip_enabled: false,
             provider_checkerproxy_enabled: true,
         }
@@ -62,7 +281,8 @@ pub fn init_config() {
                 info!("Successfully load config file");
             }
             Err(error) => {
-                error!("Load config file failed, {}", error);
+                error!("Load config file failed, {:#}", error);
+                std::process::exit(1);
             }
         }
     }
@@ -79,6 +299,9 @@ fn load_config() -> Result<Config> {
     let mut file = File::open("config.yaml")?;
     let mut yaml: String = String::new();
     file.read_to_string(&mut yaml)?;
-    let config: Config = serde_yaml::from_str(yaml.as_str())?;
+    let mut config: Config = serde_yaml::from_str(yaml.as_str())?;
+    for target in config.check_targets.iter_mut() {
+        target.compile()?;
+    }
     Ok(config)
 }
\ No newline at end of file
@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use log::{error, info};
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG;
+use crate::proxy::Proxy;
+
+/// Location and network of an ip address according to the local databases
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 code
+    pub country: Option<String>,
+    /// English city name
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    pub organization: Option<String>,
+}
+
+struct GeoIpDatabases {
+    city: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+lazy_static! {
+    static ref GEOIP_DATABASES: Arc<Mutex<GeoIpDatabases>> = Arc::new(Mutex::new(GeoIpDatabases { city: None, asn: None }));
+}
+
+/// Load the configured city and ASN databases, in MaxMind or DB-IP `.mmdb` format
+pub fn init_geoip() {
+    let (city_path, asn_path) = {
+        let config = CONFIG.lock().unwrap();
+        let config = config.as_ref().unwrap();
+        (config.geoip_city_database.clone(), config.geoip_asn_database.clone())
+    };
+    let mut databases = GEOIP_DATABASES.lock().unwrap();
+    databases.city = city_path.and_then(|path| open_database(&path));
+    databases.asn = asn_path.and_then(|path| open_database(&path));
+}
+
+fn open_database(path: &str) -> Option<Reader<Vec<u8>>> {
+    match Reader::open_readfile(path) {
+        Ok(reader) => {
+            info!("Loaded geoip database {}, {}", path, reader.metadata.database_type);
+            Some(reader)
+        }
+        Err(error) => {
+            error!("Load geoip database {} failed, {}", path, error);
+            None
+        }
+    }
+}
+
+/// Look up `ip` in the loaded databases, None if it is not a valid ip or no database knows it
+pub fn lookup(ip: &str) -> Option<GeoInfo> {
+    let ip = IpAddr::from_str(ip).ok()?;
+    let databases = GEOIP_DATABASES.lock().unwrap();
+    let mut info = GeoInfo::default();
+    if let Some(Ok(city)) = databases.city.as_ref().map(|reader| reader.lookup::<geoip2::City>(ip)) {
+        info.country = city.country.and_then(|country| country.iso_code).map(String::from);
+        info.city = city
+            .city
+            .and_then(|city| city.names)
+            .and_then(|names| names.get("en").map(|name| name.to_string()));
+    }
+    if let Some(Ok(asn)) = databases.asn.as_ref().map(|reader| reader.lookup::<geoip2::Asn>(ip)) {
+        info.asn = asn.autonomous_system_number;
+        info.organization = asn.autonomous_system_organization.map(String::from);
+    }
+    if info.country.is_none() && info.asn.is_none() {
+        return None;
+    }
+    Some(info)
+}
+
+/// Fill in the location of the entry and exit ip of the proxy.
+/// The country is replaced by the one of the exit ip, or of the entry ip if the exit is unknown,
+/// as the provider supplied one is often wrong.
+pub fn enrich(proxy: &mut Proxy) {
+    proxy.entry_geo = lookup(&proxy.proxy_ip);
+    proxy.exit_geo = proxy.exit_ip.as_deref().and_then(lookup);
+    let country = [&proxy.exit_geo, &proxy.entry_geo]
+        .into_iter()
+        .flatten()
+        .find_map(|geo| geo.country.clone());
+    if let Some(country) = country {
+        proxy.country = country;
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::{Body, Request, Response, Server};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG;
+use crate::proxy::Anonymity;
+use crate::time::current_timestamp;
+
+// Headers a proxy adds when it discloses itself to the target, compared in lowercase
+const PROXY_HEADERS: [&str; 10] = [
+    "via",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-real-ip",
+    "x-client-ip",
+    "client-ip",
+    "forwarded-for",
+    "x-proxy-id",
+    "proxy-connection",
+];
+
+/// What the judge saw of a request, a configured external judge has to answer in the same format
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JudgeEcho {
+    /// Source address of the request
+    pub ip: String,
+    /// Request headers with lowercase names
+    pub headers: HashMap<String, String>,
+}
+
+lazy_static! {
+    // Our own address as seen by the judge and when it was fetched
+    static ref REAL_IP: Arc<Mutex<Option<(String, u64)>>> = Arc::new(Mutex::new(None));
+}
+
+pub async fn init_judge_server() -> Result<()> {
+    info!("Initializing judge server");
+    let judge_port = CONFIG.lock().unwrap().as_ref().unwrap().judge_port;
+    // Proxies have to reach the judge, so unlike the other servers it listens on every interface
+    let listen_addr: SocketAddr = format!("0.0.0.0:{}", judge_port).parse()?;
+    let make_service = make_service_fn(|conn: &AddrStream| {
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle_request(request, remote_addr)))
+        }
+    });
+    let server = Server::try_bind(&listen_addr)?.serve(make_service);
+    info!("Judge server listening at {}", listen_addr);
+    server.await?;
+    Ok(())
+}
+
+async fn handle_request(request: Request<Body>, remote_addr: SocketAddr) -> Result<Response<Body>, Infallible> {
+    let echo = JudgeEcho {
+        ip: remote_addr.ip().to_string(),
+        headers: request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str().to_lowercase(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+            .collect(),
+    };
+    let response = match serde_json::to_string(&echo) {
+        Ok(json) => Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(err) => {
+            error!("Judge response serialize failed, {}", err);
+            Response::builder().status(500).body(Body::empty()).unwrap()
+        }
+    };
+    Ok(response)
+}
+
+/// Our own address as seen by the judge without any proxy, refetched once per check interval
+async fn real_ip(judge_url: &str) -> Result<String> {
+    let (check_interval, check_timeout) = {
+        let config = CONFIG.lock().unwrap();
+        let config = config.as_ref().unwrap();
+        (config.check_interval, config.check_timeout)
+    };
+    let now = current_timestamp();
+    if let Some((ip, fetched)) = REAL_IP.lock().unwrap().deref() {
+        if fetched + check_interval > now {
+            return Ok(ip.clone());
+        }
+    }
+    let client = reqwest::ClientBuilder::new()
+        .no_proxy()
+        .timeout(Duration::from_secs(check_timeout))
+        .build()?;
+    let echo: JudgeEcho = client.get(judge_url).send().await?.json().await?;
+    info!("Real ip as seen by judge is {}", echo.ip);
+    *REAL_IP.lock().unwrap() = Some((echo.ip.clone(), now));
+    Ok(echo.ip)
+}
+
+/// Addresses in a header value like `X-Forwarded-For: 1.2.3.4, 5.6.7.8` or `Forwarded: for="[2001:db8::1]:80"`
+fn header_addresses(value: &str) -> impl Iterator<Item = IpAddr> + '_ {
+    value
+        .split(|c: char| c == ',' || c == ';' || c == '=' || c.is_whitespace())
+        .filter_map(|token| parse_address(token.trim_matches('"')))
+}
+
+/// Parse an ip address, with or without brackets and port
+fn parse_address(token: &str) -> Option<IpAddr> {
+    if let Ok(ip) = token.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = token.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    token.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Request the judge through `client`, which has to be set up with the proxy, and classify what the proxy disclosed.
+/// Transparent proxies pass on our address, anonymous ones only announce themselves, elite ones look like a direct client.
+/// Also returns the source address the judge saw, which is the exit ip of the proxy.
+pub async fn classify_anonymity(client: &reqwest::Client, judge_url: &str) -> Result<(Anonymity, Option<IpAddr>)> {
+    let real_ip = real_ip(judge_url).await.context("Fetch real ip from judge failed")?;
+    let real_ip: IpAddr = real_ip.parse().with_context(|| format!("Judge reported invalid ip {}", real_ip))?;
+    let echo: JudgeEcho = client.get(judge_url).send().await?.json().await?;
+    let exit_ip = parse_address(&echo.ip);
+    if exit_ip == Some(real_ip) || echo.headers.values().any(|value| header_addresses(value).any(|ip| ip == real_ip)) {
+        return Ok((Anonymity::Transparent, exit_ip));
+    }
+    if echo.headers.keys().any(|name| PROXY_HEADERS.contains(&name.to_lowercase().as_str())) {
+        return Ok((Anonymity::Anonymous, exit_ip));
+    }
+    Ok((Anonymity::Elite, exit_ip))
+}
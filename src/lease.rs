@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::info;
+use serde::Serialize;
+
+use crate::{CONFIG, PROXY_POOL};
+use crate::proxy::Proxy;
+use crate::selector::{ProxyFilter, select_proxy};
+use crate::time::current_timestamp;
+
+/// Exclusive use of a pool proxy handed to an external consumer
+#[derive(Serialize, Debug, Clone)]
+pub struct Lease {
+    pub lease_id: String,
+    pub proxy: Proxy,
+    pub expires_at: u64,
+}
+
+lazy_static! {
+    // Active leases keyed by lease id
+    static ref LEASES: Arc<Mutex<HashMap<String, Lease>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+static LEASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn leased_addresses() -> HashSet<String> {
+    let now = current_timestamp();
+    LEASES
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|lease| lease.expires_at > now)
+        .map(|lease| lease.proxy.address())
+        .collect()
+}
+
+/// Lease a proxy matching `filter` for `ttl` seconds, capped by `lease_max_ttl`.
+/// The proxy is not selected for anything else until the lease is released or expires.
+pub fn create_lease(filter: &ProxyFilter, ttl: Option<u64>) -> Result<Lease> {
+    let (default_ttl, max_ttl) = {
+        let config = CONFIG.lock().unwrap();
+        let config = config.as_ref().unwrap();
+        (config.lease_default_ttl, config.lease_max_ttl)
+    };
+    let ttl = ttl.unwrap_or(default_ttl).min(max_ttl);
+    // Selection skips leased proxies, but another lease may grab the same proxy before this one is recorded
+    for _ in 0..3 {
+        let proxy = select_proxy(None, filter)?;
+        let now = current_timestamp();
+        let mut leases = LEASES.lock().unwrap();
+        leases.retain(|_, lease| lease.expires_at > now);
+        if leases.values().any(|lease| lease.proxy == proxy) {
+            continue;
+        }
+        let lease = Lease {
+            lease_id: format!("{:x}{:04x}", now, LEASE_COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff),
+            proxy,
+            expires_at: now + ttl,
+        };
+        info!("Leased {} as {} for {}s", lease.proxy.address(), lease.lease_id, ttl);
+        leases.insert(lease.lease_id.clone(), lease.clone());
+        return Ok(lease);
+    }
+    Err(anyhow!("No proxy available for lease"))
+}
+
+/// Release a lease, a proxy reported as bad is removed from the pool. None if there is no such lease.
+pub fn release_lease(lease_id: &str, bad: bool) -> Option<Lease> {
+    let lease = LEASES.lock().unwrap().remove(lease_id)?;
+    if bad {
+        PROXY_POOL.lock().unwrap().retain(|proxy| *proxy != lease.proxy);
+        info!("Removed proxy {} reported bad by lease {}", lease.proxy.address(), lease_id);
+    } else {
+        info!("Released lease {} of {}", lease_id, lease.proxy.address());
+    }
+    Some(lease)
+}
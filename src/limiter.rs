@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::CONFIG;
+use crate::config::DomainLimit;
+use crate::time::current_timestamp;
+
+struct DomainLimiter {
+    tunnels: Arc<Semaphore>,
+    window_start: u64,
+    window_count: u32,
+}
+
+lazy_static! {
+    // Limiter state of every domain limit rule, shared by all clients and keyed by rule domain
+    static ref DOMAIN_LIMITERS: Arc<Mutex<HashMap<String, DomainLimiter>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Held for the lifetime of a tunnel, releases the concurrency slot of the matched rule when dropped
+pub struct DomainPermit {
+    _tunnel: Option<OwnedSemaphorePermit>,
+}
+
+fn match_rule<'a>(rules: &'a [DomainLimit], host: &str) -> Option<&'a DomainLimit> {
+    rules.iter().find(|rule| {
+        let domain = rule.domain.to_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+fn domain_limiter<'a>(limiters: &'a mut HashMap<String, DomainLimiter>, rule: &DomainLimit) -> &'a mut DomainLimiter {
+    limiters.entry(rule.domain.clone()).or_insert_with(|| DomainLimiter {
+        tunnels: Arc::new(Semaphore::new(rule.max_concurrent)),
+        window_start: 0,
+        window_count: 0,
+    })
+}
+
+/// Wait for the concurrency and rate limits of the rule matching `host`.
+/// Connections which can't pass within `domain_limit_queue_timeout` seconds are rejected,
+/// a timeout of 0 rejects them immediately instead of queueing.
+pub async fn acquire_domain_permit(host: &str) -> Result<DomainPermit> {
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let rule = match match_rule(&config.domain_limits, host) {
+        Some(rule) => rule.clone(),
+        None => return Ok(DomainPermit { _tunnel: None }),
+    };
+    let deadline = Instant::now() + Duration::from_secs(config.domain_limit_queue_timeout);
+
+    let tunnel = if rule.max_concurrent > 0 {
+        let tunnels = {
+            let mut limiters = DOMAIN_LIMITERS.lock().unwrap();
+            Arc::clone(&domain_limiter(&mut limiters, &rule).tunnels)
+        };
+        let permit = match Arc::clone(&tunnels).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => tokio::time::timeout_at(deadline, tunnels.acquire_owned())
+                .await
+                .map_err(|_| anyhow!("Concurrent tunnel limit of {} reached for {}", rule.max_concurrent, host))??,
+        };
+        Some(permit)
+    } else {
+        None
+    };
+
+    if rule.max_connections_per_second > 0 {
+        loop {
+            {
+                let mut limiters = DOMAIN_LIMITERS.lock().unwrap();
+                let limiter = domain_limiter(&mut limiters, &rule);
+                let now = current_timestamp();
+                if limiter.window_start != now {
+                    limiter.window_start = now;
+                    limiter.window_count = 0;
+                }
+                if limiter.window_count < rule.max_connections_per_second {
+                    limiter.window_count += 1;
+                    break;
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow!("Connection rate limit of {}/s reached for {}", rule.max_connections_per_second, host));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    Ok(DomainPermit { _tunnel: tunnel })
+}
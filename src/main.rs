@@ -4,25 +4,48 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use lazy_static::lazy_static;
-use log::info;
+use log::{error, info};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
 use tokio::time::{Instant, interval_at, MissedTickBehavior};
 
+use crate::api::init_api_server;
 use crate::checker::check_proxy_pool;
 use crate::config::Config;
+use crate::judge::init_judge_server;
 use crate::provider::update_proxy_pool;
 use crate::proxy::{Proxy, save_proxy_pool};
 use crate::socks::init_socks_server;
 use crate::time::current_timestamp;
+#[cfg(target_os = "linux")]
+use crate::transparent::init_transparent_server;
+#[cfg(unix)]
+use crate::unix::init_unix_socks_server;
 
 mod proxy;
 mod provider;
 mod checker;
+mod prober;
+mod check_limiter;
 mod time;
 mod config;
 mod socks;
+mod socks4;
+mod proxy_protocol;
+mod limiter;
+mod session;
+mod api;
+mod judge;
+mod geoip;
+mod lease;
+mod reputation;
+mod selector;
+mod tls;
+#[cfg(unix)]
+mod unix;
+#[cfg(target_os = "linux")]
+mod transparent;
 
 lazy_static! {
     static ref CONFIG: Arc<Mutex<Option<Config>>> = Arc::new(Mutex::new(None));
@@ -44,6 +67,8 @@ async fn main() {
     env_logger::init();
     // Prepare for start up
     config::init_config();
+    check_limiter::init_check_limiter();
+    geoip::init_geoip();
     proxy::init_proxy_pool();
     // Preparation finished
     info!("Starting main thread");
@@ -51,20 +76,23 @@ async fn main() {
     main_thread.spawn(async {
         // TODO: implement socks5 server main thread
     });
-    info!("Starting proxy pool check timer");
+    info!("Starting proxy pool check scheduler");
     let proxy_pool_check_task = Runtime::new().unwrap();
     // Block initial thread on background repeat task
     proxy_pool_check_task.spawn(async {
-        let duration = Duration::from_secs(Arc::clone(&CONFIG).lock().unwrap().as_ref().unwrap().check_interval);
+        let duration = Duration::from_secs(Arc::clone(&CONFIG).lock().unwrap().as_ref().unwrap().check_tick_interval);
         let mut interval = interval_at(
             Instant::now(), duration,
         );
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
             interval.tick().await;
-            info!("Checking proxy pool {}", current_timestamp());
-            check_proxy_pool().await.unwrap();
-            save_proxy_pool().unwrap();
+            // Checks run in the background, the pool is saved once some of them finished
+            let checked = check_proxy_pool();
+            if checked > 0 {
+                info!("Checked {} proxies {}", checked, current_timestamp());
+                save_proxy_pool().unwrap();
+            }
         }
     });
 
@@ -85,6 +113,44 @@ async fn main() {
         }
     });
 
+    if Arc::clone(&CONFIG).lock().unwrap().as_ref().unwrap().api_enabled {
+        info!("Starting api server");
+        tokio::spawn(async {
+            if let Err(err) = init_api_server().await {
+                error!("Api server failed, {:#}", err);
+            }
+        });
+    }
+
+    if Arc::clone(&CONFIG).lock().unwrap().as_ref().unwrap().judge_enabled {
+        info!("Starting judge server");
+        tokio::spawn(async {
+            if let Err(err) = init_judge_server().await {
+                error!("Judge server failed, {:#}", err);
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    if Arc::clone(&CONFIG).lock().unwrap().as_ref().unwrap().transparent_proxy_enabled {
+        info!("Starting transparent proxy server");
+        tokio::spawn(async {
+            if let Err(err) = init_transparent_server().await {
+                error!("Transparent proxy server failed, {:#}", err);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    if Arc::clone(&CONFIG).lock().unwrap().as_ref().unwrap().unix_socket_enabled {
+        info!("Starting unix socks server");
+        tokio::spawn(async {
+            if let Err(err) = init_unix_socks_server().await {
+                error!("Unix socks server failed, {:#}", err);
+            }
+        });
+    }
+
     init_socks_server().await.unwrap();
 }
 
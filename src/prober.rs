@@ -0,0 +1,207 @@
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::CONFIG;
+use crate::check_limiter::acquire_check_permit;
+use crate::proxy::{Capabilities, Proxy, ProxyType};
+use crate::time::current_timestamp;
+
+// Cloudflare's public resolver, reachable over both families on ports 80 and 443
+const CAPABILITY_PROBE_IPV4: [u8; 4] = [1, 1, 1, 1];
+const CAPABILITY_PROBE_IPV6: [u16; 8] = [0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111];
+
+// SOCKS4 CONNECT requests need an ip destination, the reply tells whether the endpoint speaks SOCKS4 even if it refuses
+const SOCKS4_PROBE_TARGET: [u8; 6] = [0x01, 0xbb, 1, 1, 1, 1];
+
+/// Try the handshake of every protocol on the proxy address at once and return the ones it completes.
+/// Labels from providers are ignored, so a mislabelled or unlabelled `ip:port` gets its real protocols.
+pub async fn probe_protocols(proxy: &Proxy) -> BTreeSet<ProxyType> {
+    let _permit = acquire_check_permit().await;
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let probe_timeout = Duration::from_secs(config.check_timeout);
+    let check_url = config.check_targets.first().map(|target| target.url.clone()).unwrap_or_else(|| String::from("https://1.1.1.1/"));
+    // CONNECT to where the checks go, so proxies restricting CONNECT ports still pass
+    let connect_target = reqwest::Url::parse(&check_url)
+        .ok()
+        .and_then(|url| Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?)))
+        .unwrap_or_else(|| String::from("1.1.1.1:443"));
+
+    let mut protocols = BTreeSet::new();
+    // Dead entries are common on free lists, they cost a single connect timeout instead of one per protocol
+    if !matches!(timeout(probe_timeout, TcpStream::connect(proxy.address())).await, Ok(Ok(_))) {
+        info!("Proxy {} unreachable, skipped protocol probes", proxy.address());
+        return protocols;
+    }
+    let (socks5, socks4, http, https) = tokio::join!(
+        timeout(probe_timeout, probe_socks5(proxy)),
+        timeout(probe_timeout, probe_socks4(proxy)),
+        timeout(probe_timeout, probe_http_connect(proxy, &connect_target)),
+        timeout(probe_timeout, probe_https(proxy, &check_url, probe_timeout)),
+    );
+    let probes = [
+        (ProxyType::SOCKS5, socks5),
+        (ProxyType::SOCKS4, socks4),
+        (ProxyType::HTTP, http),
+        (ProxyType::HTTPS, https),
+    ];
+    for (proxy_type, probe) in probes {
+        if let Ok(Ok(())) = probe {
+            protocols.insert(proxy_type);
+        }
+    }
+    info!("Proxy {} speaks {:?}", proxy.address(), protocols);
+    protocols
+}
+
+/// Greeting offering only no authentication, which the proxy has to accept
+async fn probe_socks5(proxy: &Proxy) -> Result<()> {
+    socks5_handshake(proxy).await.map(|_| ())
+}
+
+async fn socks5_handshake(proxy: &Proxy) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy.address()).await?;
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [0x05, 0x00] => Ok(stream),
+        _ => Err(anyhow!("Unexpected socks5 method reply {:?}", reply)),
+    }
+}
+
+/// Send a SOCKS5 request for `command` to `addr`, returns the reply code
+async fn socks5_request(proxy: &Proxy, command: u8, addr: SocketAddr) -> Result<u8> {
+    let mut stream = socks5_handshake(proxy).await?;
+    let mut request = vec![0x05, command, 0x00];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&addr.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    Ok(reply[1])
+}
+
+async fn probe_socks4(proxy: &Proxy) -> Result<()> {
+    socks4_connect(proxy, &SOCKS4_PROBE_TARGET).await.map(|_| ())
+}
+
+/// Send a SOCKS4 CONNECT to the port and ip in `target`, returns the reply code
+async fn socks4_connect(proxy: &Proxy, target: &[u8; 6]) -> Result<u8> {
+    let mut stream = TcpStream::connect(proxy.address()).await?;
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(target);
+    request.push(0x00);
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+    match reply[..2] {
+        [0x00, code @ 0x5a..=0x5d] => Ok(code),
+        _ => Err(anyhow!("Unexpected socks4 reply {:?}", reply)),
+    }
+}
+
+async fn probe_http_connect(proxy: &Proxy, target: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(proxy.address()).await?;
+    let connect_request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    stream.write_all(connect_request.as_bytes()).await?;
+    let mut response: Vec<u8> = vec![];
+    while !response.ends_with(b"\r\n\r\n") && response.len() < 8192 {
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if response.starts_with("HTTP/1.") && status.starts_with('2') {
+        Ok(())
+    } else {
+        Err(anyhow!("Http connect refused, {}", response.lines().next().unwrap_or("")))
+    }
+}
+
+/// Send an absolute-form GET, any answer below 400 means the proxy forwards plain HTTP
+async fn probe_http_get(proxy: &Proxy, host: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(proxy.address()).await?;
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host, host);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = [0u8; 12];
+    stream.read_exact(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status: u16 = response.split_whitespace().nth(1).unwrap_or("").parse()?;
+    if response.starts_with("HTTP/1.") && status < 400 {
+        Ok(())
+    } else {
+        Err(anyhow!("Http get refused with status {}", status))
+    }
+}
+
+/// Probe what the proxy can do over its relay protocol
+pub async fn probe_capabilities(proxy: &Proxy) -> Capabilities {
+    let _permit = acquire_check_permit().await;
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let probe_timeout = Duration::from_secs(config.check_timeout);
+    let ipv4_http = SocketAddr::from((CAPABILITY_PROBE_IPV4, 80));
+    let ipv6_https = SocketAddr::from((CAPABILITY_PROBE_IPV6, 443));
+    let passed = |probe: Result<Result<()>, _>| Some(matches!(probe, Ok(Ok(()))));
+    let mut capabilities = Capabilities {
+        last_probed: current_timestamp(),
+        ..Capabilities::default()
+    };
+    match proxy.proxy_type {
+        ProxyType::SOCKS5 => {
+            let granted = |probe: Result<Result<u8>, _>| Some(matches!(probe, Ok(Ok(0x00))));
+            capabilities.connect_any_port = granted(timeout(probe_timeout, socks5_request(proxy, 0x01, ipv4_http)).await);
+            capabilities.ipv6 = granted(timeout(probe_timeout, socks5_request(proxy, 0x01, ipv6_https)).await);
+            let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+            capabilities.udp_associate = granted(timeout(probe_timeout, socks5_request(proxy, 0x03, unspecified)).await);
+            capabilities.http_get = Some(false);
+        }
+        ProxyType::SOCKS4 => {
+            let mut target = [0u8; 6];
+            target[..2].copy_from_slice(&80u16.to_be_bytes());
+            target[2..].copy_from_slice(&CAPABILITY_PROBE_IPV4);
+            let probe = timeout(probe_timeout, socks4_connect(proxy, &target)).await;
+            capabilities.connect_any_port = Some(matches!(probe, Ok(Ok(0x5a))));
+            capabilities.ipv6 = Some(false);
+            capabilities.udp_associate = Some(false);
+            capabilities.http_get = Some(false);
+        }
+        ProxyType::HTTP => {
+            capabilities.connect_any_port = passed(timeout(probe_timeout, probe_http_connect(proxy, &ipv4_http.to_string())).await);
+            capabilities.ipv6 = passed(timeout(probe_timeout, probe_http_connect(proxy, &ipv6_https.to_string())).await);
+            capabilities.udp_associate = Some(false);
+            let ipv4_host = IpAddr::from(CAPABILITY_PROBE_IPV4).to_string();
+            capabilities.http_get = passed(timeout(probe_timeout, probe_http_get(proxy, &ipv4_host)).await);
+        }
+        // Not relayed through, so there is nothing to select by
+        ProxyType::HTTPS => {}
+    }
+    info!("Proxy {} capabilities {:?}", proxy.address(), capabilities);
+    capabilities
+}
+
+/// Request the first check target through the proxy over TLS, proxies rarely have a trusted certificate
+async fn probe_https(proxy: &Proxy, url: &str, probe_timeout: Duration) -> Result<()> {
+    let client = reqwest::ClientBuilder::new()
+        .proxy(reqwest::Proxy::all(format!("https://{}", proxy.address()))?)
+        .danger_accept_invalid_certs(true)
+        .timeout(probe_timeout)
+        .build()?;
+    client.get(url).send().await?;
+    Ok(())
+}
@@ -63,8 +63,11 @@ pub async fn update_proxy_pool() -> Result<()> {
     }
     {
         let mut proxy_pool = PROXY_POOL.lock().unwrap();
+        // Proxies already in the pool keep their entry, inserting would add a second one as `last_used` differs
         for proxy in proxies.lock().unwrap().iter() {
-            proxy_pool.insert((*proxy).clone());
+            if !proxy_pool.iter().any(|entry| entry == proxy) {
+                proxy_pool.insert((*proxy).clone());
+            }
         }
     }
     info!("Update proxy pool successfully at {}", current_timestamp());
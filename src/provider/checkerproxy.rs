@@ -1,14 +1,16 @@
+use std::collections::BTreeSet;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use log::info;
 use serde::Deserialize;
-use tokio::sync::Semaphore;
 
 use crate::checker;
+use crate::geoip::enrich;
+use crate::prober::{probe_capabilities, probe_protocols};
 use crate::provider::ProxyProvider;
-use crate::proxy::{Proxy, ProxyType};
+use crate::proxy::{Capabilities, Health, Latency, Proxy, ProxyType};
 use crate::time::current_timestamp;
 
 pub struct CheckerProxyProvider {
@@ -59,7 +61,6 @@ impl ProxyProvider for CheckerProxyProvider {
         // Check proxy available
         let proxies: Arc<Mutex<Vec<Proxy>>> = Arc::new(Mutex::new(Vec::new()));
         let mut tasks = Vec::new();
-        let semaphore = Arc::new(Semaphore::new(10));
         for data in datas {
             let (address, port) = data.ip.split_once(":").unwrap();
             let address = address.to_string();
@@ -78,6 +79,15 @@ impl ProxyProvider for CheckerProxyProvider {
                 country: data.country,
                 last_checked: 0,
                 last_used: 0,
+                protocols: BTreeSet::new(),
+                latency: Latency::default(),
+                anonymity: None,
+                exit_ip: None,
+                entry_geo: None,
+                exit_geo: None,
+                health: Health::default(),
+                capabilities: Capabilities::default(),
+                next_check: 0,
             };
             // TODO: Implement http proxy chain and remove this
             // if proxy.proxy_type == ProxyType::HTTP {
@@ -85,14 +95,26 @@ impl ProxyProvider for CheckerProxyProvider {
             // }
 
             let proxies = Arc::clone(&proxies);
-            let semaphore = Arc::clone(&semaphore);
             tasks.push(tokio::spawn(async move {
-                let _ = semaphore.acquire().await.unwrap();
-                let status = checker::check_proxy(&proxy).await;
-                if status.as_ref().is_err() {
+                // Nothing completed a handshake, so the check could only fail after its own timeout
+                let protocols = probe_protocols(&proxy).await;
+                if protocols.is_empty() {
                     info!("Proxy {} unavailable", proxy.proxy_ip);
                     return;
                 }
+                proxy.set_protocols(protocols);
+                let result = match checker::check_proxy(&proxy).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        info!("Proxy {} unavailable", proxy.proxy_ip);
+                        return;
+                    }
+                };
+                proxy.latency.record(result.latency);
+                proxy.anonymity = result.anonymity;
+                proxy.exit_ip = result.exit_ip;
+                enrich(&mut proxy);
+                proxy.capabilities = probe_capabilities(&proxy).await;
                 proxy.last_checked = current_timestamp();
                 proxy.last_used = current_timestamp();
                 info!("Proxy {} available", proxy.proxy_ip);
@@ -5,12 +5,15 @@ use std::hash::{Hash, Hasher};
 use std::io::{Read, Write};
 use std::ops::Deref;
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use fast_socks5::util::target_addr::TargetAddr;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
 
 use crate::PROXY_POOL;
+use crate::geoip::GeoInfo;
 use crate::time::current_timestamp;
 
 #[derive(Deserialize, Serialize, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
@@ -21,30 +24,270 @@ pub enum ProxyType {
     SOCKS4,
 }
 
+impl FromStr for ProxyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "HTTP" => Ok(ProxyType::HTTP),
+            "HTTPS" => Ok(ProxyType::HTTPS),
+            "SOCKS5" => Ok(ProxyType::SOCKS5),
+            "SOCKS4" => Ok(ProxyType::SOCKS4),
+            _ => Err(anyhow!("Unknown proxy type {}", s)),
+        }
+    }
+}
+
+// Protocols the tunnel speaks to upstreams, in the order the relay prefers them
+const RELAY_PREFERENCE: [ProxyType; 2] = [ProxyType::SOCKS5, ProxyType::HTTP];
+
+/// How much a proxy discloses about its client, ordered from least to most anonymous
+#[derive(Deserialize, Serialize, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Anonymity {
+    /// Passes on the client address
+    Transparent,
+    /// Hides the client address but announces itself with headers like `Via` or `X-Forwarded-For`
+    Anonymous,
+    /// Looks like a direct client
+    Elite,
+}
+
+impl FromStr for Anonymity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "transparent" => Ok(Anonymity::Transparent),
+            "anonymous" => Ok(Anonymity::Anonymous),
+            "elite" => Ok(Anonymity::Elite),
+            _ => Err(anyhow!("Unknown anonymity {}", s)),
+        }
+    }
+}
+
+/// Timings of a single check, in milliseconds
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+pub struct LatencySample {
+    /// From connecting to the proxy until it granted a tunnel to the check target
+    #[serde(default)]
+    pub connect_ms: u64,
+    /// From sending the check request until the response headers arrived
+    pub first_byte_ms: u64,
+    /// From sending the check request until the whole response body arrived
+    pub total_ms: u64,
+}
+
+// Number of recent samples the rolling average and percentile are computed over
+const LATENCY_WINDOW: usize = 20;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Latency {
+    #[serde(default)]
+    pub average_connect_ms: u64,
+    pub average_first_byte_ms: u64,
+    pub average_total_ms: u64,
+    pub p90_total_ms: u64,
+    pub samples: Vec<LatencySample>,
+}
+
+impl Latency {
+    pub fn record(&mut self, sample: LatencySample) {
+        self.samples.push(sample);
+        if self.samples.len() > LATENCY_WINDOW {
+            self.samples.remove(0);
+        }
+        let count = self.samples.len() as u64;
+        self.average_connect_ms = self.samples.iter().map(|sample| sample.connect_ms).sum::<u64>() / count;
+        self.average_first_byte_ms = self.samples.iter().map(|sample| sample.first_byte_ms).sum::<u64>() / count;
+        self.average_total_ms = self.samples.iter().map(|sample| sample.total_ms).sum::<u64>() / count;
+        let mut totals: Vec<u64> = self.samples.iter().map(|sample| sample.total_ms).collect();
+        totals.sort();
+        self.p90_total_ms = totals[(totals.len() * 9 / 10).min(totals.len() - 1)];
+    }
+}
+
+/// What the proxy can do over its relay protocol, None where not probed or not applicable
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Tunnels to ports other than 443
+    pub connect_any_port: Option<bool>,
+    /// Tunnels to IPv6 destinations
+    pub ipv6: Option<bool>,
+    /// Accepts SOCKS5 UDP ASSOCIATE
+    pub udp_associate: Option<bool>,
+    /// Forwards plain HTTP GET requests, only HTTP proxies do
+    pub http_get: Option<bool>,
+    pub last_probed: u64,
+}
+
+impl Capabilities {
+    /// Whether nothing probed so far rules out tunneling to `target`
+    pub fn can_reach(&self, target: &TargetAddr) -> bool {
+        let (ipv6, port) = match target {
+            TargetAddr::Ip(addr) => (addr.is_ipv6(), addr.port()),
+            TargetAddr::Domain(_, port) => (false, *port),
+        };
+        let ipv6_refused = ipv6 && self.ipv6 == Some(false);
+        let port_refused = port != 443 && self.connect_any_port == Some(false);
+        !ipv6_refused && !port_refused
+    }
+}
+
+/// Check history of a proxy
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Health {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_successes: u32,
+    pub consecutive_failures: u32,
+    /// Results of the latest checks, oldest first
+    pub recent: Vec<bool>,
+}
+
+impl Health {
+    /// Record a check result, keeping the latest `window` results
+    pub fn record(&mut self, success: bool, window: usize) {
+        if success {
+            self.successes += 1;
+            self.consecutive_successes += 1;
+            self.consecutive_failures = 0;
+        } else {
+            self.failures += 1;
+            self.consecutive_successes = 0;
+            self.consecutive_failures += 1;
+        }
+        self.recent.push(success);
+        if self.recent.len() > window.max(1) {
+            self.recent.remove(0);
+        }
+    }
+
+    /// Share of passed checks among the recent ones, 1 if never checked
+    pub fn success_rate(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 1.0;
+        }
+        self.recent.iter().filter(|success| **success).count() as f64 / self.recent.len() as f64
+    }
+
+    /// The latest check failed, the proxy stays in the pool but is only selected when no healthy proxy is left
+    pub fn is_degraded(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Proxy {
+    /// Protocol used to relay through the proxy, the preferred one of `protocols` once probed
     pub proxy_type: ProxyType,
+    /// Every protocol the proxy completed a handshake for, empty until probed
+    #[serde(default)]
+    pub protocols: BTreeSet<ProxyType>,
     pub proxy_ip: String,
     pub proxy_port: i32,
     pub country: String,
     pub last_checked: u64,
     pub last_used: u64,
+    #[serde(default)]
+    pub latency: Latency,
+    /// Unknown until checked against a judge
+    #[serde(default)]
+    pub anonymity: Option<Anonymity>,
+    /// Address the proxy connects to targets from, as reported by the check target
+    #[serde(default)]
+    pub exit_ip: Option<String>,
+    #[serde(default)]
+    pub entry_geo: Option<GeoInfo>,
+    #[serde(default)]
+    pub exit_geo: Option<GeoInfo>,
+    #[serde(default)]
+    pub health: Health,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Timestamp the proxy is due for its next check, 0 if not scheduled yet
+    #[serde(default)]
+    pub next_check: u64,
+}
+
+impl Proxy {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.proxy_ip, self.proxy_port)
+    }
+
+    /// Whether the proxy speaks `proxy_type`, trusting the provider label until probed
+    pub fn supports(&self, proxy_type: ProxyType) -> bool {
+        self.proxy_type == proxy_type || self.protocols.contains(&proxy_type)
+    }
+
+    /// Record the probed protocols and relay through the preferred one.
+    /// The current type is kept if none of them can be relayed through, see `is_relayable`.
+    pub fn set_protocols(&mut self, protocols: BTreeSet<ProxyType>) {
+        if let Some(proxy_type) = RELAY_PREFERENCE.iter().find(|proxy_type| protocols.contains(proxy_type)) {
+            self.proxy_type = *proxy_type;
+        }
+        self.protocols = protocols;
+    }
+
+    /// Whether the tunnel can relay through the proxy, trusting the provider label until probed
+    pub fn is_relayable(&self) -> bool {
+        RELAY_PREFERENCE.contains(&self.proxy_type) && (self.protocols.is_empty() || self.protocols.contains(&self.proxy_type))
+    }
+
+    /// Key proxies are rotated by, proxies sharing an exit ip share one key when `by_exit_ip` is set
+    pub fn rotation_key(&self, by_exit_ip: bool) -> String {
+        match &self.exit_ip {
+            Some(exit_ip) if by_exit_ip => exit_ip.clone(),
+            _ => self.address(),
+        }
+    }
+
+    /// ASN of the exit ip, or of the entry ip if the exit is unknown
+    pub fn asn(&self) -> Option<u32> {
+        [&self.exit_geo, &self.entry_geo].into_iter().flatten().find_map(|geo| geo.asn)
+    }
+
+    /// Score from 0 to 100, losing a point for every 100ms of average check time.
+    /// Proxies which were never measured score 0.
+    pub fn score(&self) -> u64 {
+        if self.latency.samples.is_empty() {
+            return 0;
+        }
+        100 - (self.latency.average_total_ms / 100).min(100)
+    }
+}
+
+/// Apply `update` to the pool entry of `proxy`, returns false if the proxy left the pool.
+/// The entry is looked up by address rather than by ordering, as its `last_used` may have changed since `proxy` was cloned.
+pub fn update_pool_proxy<F>(proxy_pool: &mut BTreeSet<Proxy>, proxy: &Proxy, update: F) -> bool
+    where
+        F: FnOnce(&mut Proxy),
+{
+    let mut entry = match proxy_pool.iter().find(|entry| *entry == proxy) {
+        Some(entry) => entry.clone(),
+        None => return false,
+    };
+    proxy_pool.remove(&entry);
+    update(&mut entry);
+    proxy_pool.insert(entry);
+    true
 }
 
 impl Hash for Proxy {
     fn hash<H: Hasher>(&self, state: &mut H) {
         info!("Hasher called");
-        self.proxy_type.hash(state);
         self.proxy_ip.hash(state);
         self.proxy_port.hash(state);
         self.country.hash(state);
     }
 }
 
+// A proxy is identified by its endpoint, whatever protocols it speaks
 impl PartialEq for Proxy {
     fn eq(&self, other: &Self) -> bool {
-        self.proxy_type == other.proxy_type &&
-            self.proxy_ip == other.proxy_ip &&
+        self.proxy_ip == other.proxy_ip &&
             self.proxy_port == other.proxy_port
     }
 }
@@ -53,10 +296,13 @@ impl Eq for Proxy {}
 
 impl PartialOrd for Proxy {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.proxy_ip == other.proxy_ip && self.proxy_port == other.proxy_port && self.proxy_type == other.proxy_type {
+        if self.proxy_ip == other.proxy_ip && self.proxy_port == other.proxy_port {
             return Some(Ordering::Equal);
         }
-        Some(self.last_used.cmp(&other.last_used))
+        // Break ties by address, otherwise different proxies used in the same second would collapse into one entry
+        Some(self.last_used.cmp(&other.last_used)
+            .then_with(|| self.proxy_ip.cmp(&other.proxy_ip))
+            .then_with(|| self.proxy_port.cmp(&other.proxy_port)))
     }
 }
 
@@ -0,0 +1,179 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// The longest possible v1 header, including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+
+pub fn is_trusted_source(addr: &IpAddr, trusted_sources: &[String]) -> bool {
+    trusted_sources.iter().any(|source| {
+        match source.parse::<IpNet>() {
+            Ok(net) => net.contains(addr),
+            Err(_) => source.parse::<IpAddr>().map(|ip| ip == *addr).unwrap_or(false),
+        }
+    })
+}
+
+/// Read a PROXY protocol v1 or v2 header from the start of the stream.
+/// Only the header is consumed, returns `None` for LOCAL / UNKNOWN headers which carry no client address.
+pub async fn read_proxy_header<T>(stream: &mut T) -> Result<Option<SocketAddr>>
+    where
+        T: AsyncRead + Unpin,
+{
+    // Both versions are at least 12 bytes long, so this never reads past the header
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await.context("Read proxy protocol header")?;
+    if head == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if head.starts_with(V1_PREFIX) {
+        read_v1(stream, &head).await
+    } else {
+        Err(anyhow!("Missing proxy protocol header"))
+    }
+}
+
+async fn read_v1<T>(stream: &mut T, head: &[u8]) -> Result<Option<SocketAddr>>
+    where
+        T: AsyncRead + Unpin,
+{
+    let mut header: Vec<u8> = head.to_vec();
+    while !header.ends_with(&[0x0d, 0x0a]) {
+        if header.len() >= V1_MAX_LENGTH {
+            return Err(anyhow!("Proxy protocol v1 header too long"));
+        }
+        header.push(stream.read_u8().await.context("Read proxy protocol v1 header")?);
+    }
+    let header = String::from_utf8(header).context("Proxy protocol v1 header is not utf8")?;
+    let fields: Vec<&str> = header.trim_end().split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip = fields[2].parse::<IpAddr>().context("Parse proxy protocol v1 source address")?;
+            let port = fields[4].parse::<u16>().context("Parse proxy protocol v1 source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("Malformed proxy protocol v1 header {}", header.trim_end()))
+    }
+}
+
+async fn read_v2<T>(stream: &mut T) -> Result<Option<SocketAddr>>
+    where
+        T: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload).await.context("Read proxy protocol v2 addresses")?;
+
+    if version_command >> 4 != 0x2 {
+        return Err(anyhow!("Unsupported proxy protocol version {}", version_command >> 4));
+    }
+    match version_command & 0x0f {
+        // LOCAL, health checks from the balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => return Err(anyhow!("Unsupported proxy protocol v2 command {}", command))
+    }
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[0..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry nothing we can use as a client address
+        0x0 | 0x3 => Ok(None),
+        _ => Err(anyhow!("Malformed proxy protocol v2 address block"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let cases: [(&[u8], Option<SocketAddr>); 3] = [
+            (b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n", Some("192.0.2.1:56324".parse().unwrap())),
+            (b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n", Some("[2001:db8::1]:56324".parse().unwrap())),
+            (b"PROXY UNKNOWN\r\n", None),
+        ];
+        for (header, expected) in cases {
+            let mut stream = header;
+            assert_eq!(read_proxy_header(&mut stream).await.unwrap(), expected, "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1_headers() {
+        let cases: [&[u8]; 3] = [
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 not-an-ip 198.51.100.1 56324 443\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ];
+        for header in cases {
+            let mut stream = header;
+            assert!(read_proxy_header(&mut stream).await.is_err(), "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let tcp4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let mut tcp6 = vec![0u8; 36];
+        tcp6[0..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        tcp6[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        let cases = [
+            (v2_header(0x21, 0x11, &tcp4), Some("192.0.2.1:56324".parse().unwrap())),
+            (v2_header(0x21, 0x21, &tcp6), Some("[2001:db8::1]:56324".parse().unwrap())),
+            (v2_header(0x20, 0x00, &[]), None),
+            (v2_header(0x21, 0x00, &[]), None),
+        ];
+        for (header, expected) in cases {
+            let mut stream = header.as_slice();
+            assert_eq!(read_proxy_header(&mut stream).await.unwrap(), expected, "{:02x?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v2_headers() {
+        let cases = [
+            // TCP4 with an address block too short for two addresses and ports
+            v2_header(0x21, 0x11, &[192, 0, 2, 1]),
+            // Block length larger than what follows
+            v2_header(0x21, 0x11, &[192, 0, 2, 1])[..17].to_vec(),
+            v2_header(0x11, 0x11, &[0u8; 12]),
+            v2_header(0x22, 0x11, &[0u8; 12]),
+        ];
+        for header in cases {
+            let mut stream = header.as_slice();
+            assert!(read_proxy_header(&mut stream).await.is_err(), "{:02x?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_the_stream_after_the_header() {
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n\x05\x01\x00";
+        read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"\x05\x01\x00");
+    }
+}
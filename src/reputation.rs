@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::CONFIG;
+use crate::time::current_timestamp;
+
+struct DomainReputation {
+    bad_reports: u32,
+    updated: u64,
+}
+
+lazy_static! {
+    // Client reported reputation keyed by (proxy address, domain)
+    static ref REPUTATION: Arc<Mutex<HashMap<(String, String), DomainReputation>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Record client feedback about a proxy for a domain, a good report takes back one bad report
+pub fn report(address: &str, domain: &str, bad: bool) {
+    let now = current_timestamp();
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let ttl = CONFIG.lock().unwrap().as_ref().unwrap().reputation_ttl;
+    let mut reputation = REPUTATION.lock().unwrap();
+    reputation.retain(|_, entry| entry.updated + ttl > now);
+    let entry = reputation
+        .entry((address.to_string(), domain.clone()))
+        .or_insert(DomainReputation { bad_reports: 0, updated: now });
+    entry.bad_reports = if bad { entry.bad_reports + 1 } else { entry.bad_reports.saturating_sub(1) };
+    entry.updated = now;
+    info!("Proxy {} reported {} for {}, {} bad reports", address, if bad { "bad" } else { "good" }, domain, entry.bad_reports);
+}
+
+/// Whether the proxy collected enough bad reports for `host` or one of its parent domains.
+/// Reports expire `reputation_ttl` seconds after the last one.
+pub fn is_bad_for(address: &str, host: &str) -> bool {
+    let (threshold, ttl) = {
+        let config = CONFIG.lock().unwrap();
+        let config = config.as_ref().unwrap();
+        (config.reputation_bad_threshold, config.reputation_ttl)
+    };
+    let now = current_timestamp();
+    let reputation = REPUTATION.lock().unwrap();
+    let mut domain = host;
+    loop {
+        if let Some(entry) = reputation.get(&(address.to_string(), domain.to_string())) {
+            if entry.bad_reports >= threshold && entry.updated + ttl > now {
+                return true;
+            }
+        }
+        match domain.split_once('.') {
+            Some((_, parent)) if parent.contains('.') => domain = parent,
+            _ => return false,
+        }
+    }
+}
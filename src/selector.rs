@@ -0,0 +1,254 @@
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use fast_socks5::util::target_addr::TargetAddr;
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{CONFIG, PROXY_POOL};
+use crate::config::Config;
+use crate::lease::leased_addresses;
+use crate::proxy::{Anonymity, Proxy, ProxyType};
+use crate::reputation::is_bad_for;
+use crate::time::current_timestamp;
+
+lazy_static! {
+    // Recent use timestamps of every proxy handed out, keyed by proxy rotation key
+    static ref PROXY_USAGE: Arc<Mutex<HashMap<String, VecDeque<u64>>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Restricts which proxies of the pool may be selected, an empty list allows everything
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ProxyFilter {
+    pub countries: Vec<String>,
+    pub proxy_types: Vec<ProxyType>,
+    /// Upper bound of the average total check time
+    pub max_latency_ms: Option<u64>,
+    /// Lower bound of the latency score, see `Proxy::score`
+    pub min_score: Option<u64>,
+    /// Exit ASNs, see `Proxy::asn`
+    pub asns: Vec<u32>,
+    /// Skip proxies in `datacenter_asns`
+    pub exclude_datacenter: bool,
+    /// Only proxies known to accept SOCKS5 UDP ASSOCIATE
+    pub udp_associate: bool,
+    /// Only proxies known to forward plain HTTP GET requests
+    pub http_get: bool,
+    /// Least anonymity level, unclassified proxies never match
+    pub min_anonymity: Option<Anonymity>,
+    /// Addresses of proxies which must not be selected
+    #[serde(skip)]
+    pub excluded: Vec<String>,
+}
+
+impl ProxyFilter {
+    pub fn matches(&self, proxy: &Proxy, config: &Config) -> bool {
+        (self.countries.is_empty() || self.countries.iter().any(|country| country.eq_ignore_ascii_case(&proxy.country)))
+            && (self.proxy_types.is_empty() || self.proxy_types.iter().any(|proxy_type| proxy.supports(*proxy_type)))
+            && match self.max_latency_ms {
+                Some(max_latency_ms) => !proxy.latency.samples.is_empty() && proxy.latency.average_total_ms <= max_latency_ms,
+                None => true,
+            }
+            && match self.min_score {
+                Some(min_score) => proxy.score() >= min_score,
+                None => true,
+            }
+            && (self.asns.is_empty() || proxy.asn().is_some_and(|asn| self.asns.contains(&asn)))
+            && !(self.exclude_datacenter && proxy.asn().is_some_and(|asn| config.datacenter_asns.contains(&asn)))
+            && (!self.udp_associate || proxy.capabilities.udp_associate == Some(true))
+            && (!self.http_get || proxy.capabilities.http_get == Some(true))
+            && match self.min_anonymity {
+                Some(min_anonymity) => proxy.anonymity.is_some_and(|anonymity| anonymity >= min_anonymity),
+                None => true,
+            }
+            && !self.excluded.contains(&proxy.address())
+    }
+}
+
+/// Why a proxy matching the filter was passed over, a proxy counts only for the first reason that applies
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq)]
+enum Exclusion {
+    NotRelayable,
+    Leased,
+    BadForTarget,
+    Unreachable,
+    CoolingDown,
+}
+
+impl Display for Exclusion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Exclusion::NotRelayable => write!(f, "not relayable"),
+            Exclusion::Leased => write!(f, "leased"),
+            Exclusion::BadForTarget => write!(f, "reported bad for the target"),
+            Exclusion::Unreachable => write!(f, "unable to reach the target"),
+            Exclusion::CoolingDown => write!(f, "cooling down"),
+        }
+    }
+}
+
+/// Pick a proxy matching `filter` from the pool for a connection to `target`, and mark it as used.
+/// Without affinity (or without a target) the least recently used proxy is picked, so connections rotate through the pool,
+/// the faster one wins among proxies last used in the same second.
+/// Proxies still inside their reuse interval or over their per minute request cap are skipped,
+/// as well as proxies clients reported bad for the target domain.
+/// Proxies the tunnel can't relay through, speaking only SOCKS4 or HTTPS, are never picked.
+/// Proxies probed unable to reach the target, by port or by address family, are skipped.
+/// Degraded proxies, whose latest check failed, are only picked when no healthy candidate is left.
+/// With `proxy_dedup_by_exit_ip` proxies sharing an exit ip are rotated, cooled down and capped as one.
+pub fn select_proxy(target: Option<&TargetAddr>, filter: &ProxyFilter) -> Result<Proxy> {
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let now = current_timestamp();
+    let leased = leased_addresses();
+    let mut proxy_pool = PROXY_POOL.lock().unwrap();
+    let mut proxy_usage = PROXY_USAGE.lock().unwrap();
+    if proxy_pool.is_empty() {
+        return Err(anyhow!("Proxy pool is empty"));
+    }
+
+    let matched: Vec<&Proxy> = proxy_pool.iter().filter(|proxy| filter.matches(proxy, &config)).collect();
+    if matched.is_empty() {
+        return Err(anyhow!("No proxy in pool matches {:?}", filter));
+    }
+    let mut exclusions: BTreeMap<Exclusion, usize> = BTreeMap::new();
+    let candidates: Vec<&Proxy> = matched
+        .iter()
+        .filter(|proxy| {
+            let exclusion = if !proxy.is_relayable() {
+                Some(Exclusion::NotRelayable)
+            } else if leased.contains(&proxy.address()) {
+                Some(Exclusion::Leased)
+            } else if target.is_some_and(|target| is_bad_for(&proxy.address(), &target_host(target))) {
+                Some(Exclusion::BadForTarget)
+            } else if target.is_some_and(|target| !proxy.capabilities.can_reach(target)) {
+                Some(Exclusion::Unreachable)
+            } else if is_cooling_down(proxy_usage.get(&proxy.rotation_key(config.proxy_dedup_by_exit_ip)), now, &config) {
+                Some(Exclusion::CoolingDown)
+            } else {
+                None
+            };
+            match exclusion {
+                Some(exclusion) => {
+                    *exclusions.entry(exclusion).or_default() += 1;
+                    false
+                }
+                None => true,
+            }
+        })
+        .copied()
+        .collect();
+    let healthy: Vec<&Proxy> = candidates.iter().filter(|proxy| !proxy.health.is_degraded()).copied().collect();
+    let candidates = if healthy.is_empty() { candidates } else { healthy };
+    // A proxy counts as used as recently as any other proxy sharing its rotation key
+    let mut key_last_used: HashMap<String, u64> = HashMap::new();
+    for proxy in matched.iter() {
+        let last_used = key_last_used.entry(proxy.rotation_key(config.proxy_dedup_by_exit_ip)).or_default();
+        *last_used = (*last_used).max(proxy.last_used);
+    }
+    let selected = match target {
+        Some(target) if config.proxy_affinity_enabled => affinity_proxy(&candidates, &target_host(target)),
+        _ => candidates
+            .iter()
+            .min_by_key(|proxy| (key_last_used[&proxy.rotation_key(config.proxy_dedup_by_exit_ip)], Reverse(proxy.score())))
+            .map(|proxy| (*proxy).clone()),
+    };
+    let selected = match selected {
+        Some(proxy) => proxy,
+        None => {
+            let message = if exclusions.get(&Exclusion::CoolingDown) == Some(&matched.len()) {
+                format!("All {} matching proxies are cooling down", matched.len())
+            } else {
+                let reasons: Vec<String> = exclusions.iter().map(|(exclusion, count)| format!("{} {}", count, exclusion)).collect();
+                format!("None of {} matching proxies is selectable, {}", matched.len(), reasons.join(", "))
+            };
+            warn!("{}", message);
+            return Err(anyhow!(message));
+        }
+    };
+
+    Ok(mark_used(&mut proxy_pool, &mut proxy_usage, selected, now, &config))
+}
+
+/// Pick the proxy at `address` again if it is still in the pool, not leased, not reported bad for the target
+/// and able to reach it, regardless of its cooldown. Used to keep a session on the proxy it is bound to.
+pub fn reselect_proxy(address: &str, target: &TargetAddr) -> Option<Proxy> {
+    if leased_addresses().contains(address) || is_bad_for(address, &target_host(target)) {
+        return None;
+    }
+    let config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let mut proxy_pool = PROXY_POOL.lock().unwrap();
+    let mut proxy_usage = PROXY_USAGE.lock().unwrap();
+    let selected = proxy_pool.iter().find(|proxy| proxy.address() == address)?.clone();
+    if !selected.is_relayable() || !selected.capabilities.can_reach(target) {
+        return None;
+    }
+    Some(mark_used(&mut proxy_pool, &mut proxy_usage, selected, current_timestamp(), &config))
+}
+
+fn mark_used(
+    proxy_pool: &mut BTreeSet<Proxy>,
+    proxy_usage: &mut HashMap<String, VecDeque<u64>>,
+    selected: Proxy,
+    now: u64,
+    config: &Config,
+) -> Proxy {
+    let usage = proxy_usage.entry(selected.rotation_key(config.proxy_dedup_by_exit_ip)).or_default();
+    usage.push_back(now);
+    while usage.len() > config.proxy_max_requests_per_minute.max(1) {
+        usage.pop_front();
+    }
+
+    proxy_pool.remove(&selected);
+    let mut proxy = selected;
+    proxy.last_used = now;
+    proxy_pool.insert(proxy.clone());
+    proxy
+}
+
+pub fn target_host(target: &TargetAddr) -> String {
+    match target {
+        TargetAddr::Ip(addr) => addr.ip().to_string(),
+        TargetAddr::Domain(domain, _) => domain.to_lowercase(),
+    }
+}
+
+fn is_cooling_down(usage: Option<&VecDeque<u64>>, now: u64, config: &Config) -> bool {
+    let usage = match usage {
+        Some(usage) => usage,
+        None => return false,
+    };
+    if let Some(last_used) = usage.back() {
+        if config.proxy_reuse_interval > 0 && now < last_used + config.proxy_reuse_interval {
+            return true;
+        }
+    }
+    // Only the latest `proxy_max_requests_per_minute` uses are kept, so the cap is hit when the oldest is within a minute
+    config.proxy_max_requests_per_minute > 0
+        && usage.len() >= config.proxy_max_requests_per_minute
+        && usage.front().is_some_and(|first_used| now < first_used + 60)
+}
+
+/// Rendezvous hashing, every proxy gets a weight for the host and the heaviest one wins.
+/// A host keeps its proxy as long as that proxy stays available, and removing or adding
+/// a proxy only moves the hosts which were (or become) mapped to it.
+fn affinity_proxy(candidates: &[&Proxy], host: &str) -> Option<Proxy> {
+    candidates
+        .iter()
+        .max_by_key(|proxy| {
+            let mut hasher = DefaultHasher::new();
+            host.hash(&mut hasher);
+            proxy.proxy_ip.hash(&mut hasher);
+            proxy.proxy_port.hash(&mut hasher);
+            hasher.finish()
+        })
+        .map(|proxy| (*proxy).clone())
+}
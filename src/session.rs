@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use fast_socks5::server::Authentication;
+use fast_socks5::util::target_addr::TargetAddr;
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::CONFIG;
+use crate::proxy::Proxy;
+use crate::selector::{ProxyFilter, reselect_proxy, select_proxy};
+use crate::time::current_timestamp;
+
+/// A client session, all of its connections go through the same proxy until it is rotated
+struct Session {
+    proxy: Option<String>,
+    filter: ProxyFilter,
+    blacklist: Vec<String>,
+    last_active: u64,
+}
+
+lazy_static! {
+    // Sessions keyed by session id, which is the socks username
+    static ref SESSIONS: Arc<Mutex<HashMap<String, Session>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Accepts any credentials, the username is only used as session id
+pub struct SessionAuthentication;
+
+impl Authentication for SessionAuthentication {
+    fn authenticate(&self, _username: &str, _password: &str) -> bool {
+        true
+    }
+}
+
+/// Pick the proxy bound to the session, binding a newly selected one when the session is new or its proxy left the pool
+pub fn select_session_proxy(session_id: &str, target: &TargetAddr, filter: &ProxyFilter) -> Result<Proxy> {
+    let session_ttl = CONFIG.lock().unwrap().as_ref().unwrap().session_ttl;
+    let now = current_timestamp();
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| session.last_active + session_ttl > now);
+    let session = sessions.entry(session_id.to_string()).or_insert_with(|| Session {
+        proxy: None,
+        filter: filter.clone(),
+        blacklist: vec![],
+        last_active: now,
+    });
+    session.last_active = now;
+    if let Some(proxy) = session.proxy.as_deref().and_then(|address| reselect_proxy(address, target)) {
+        return Ok(proxy);
+    }
+
+    let mut filter = session.filter.clone();
+    filter.excluded = session.blacklist.clone();
+    let proxy = select_proxy(Some(target), &filter)?;
+    info!("Session {} bound to {}", session_id, proxy.address());
+    session.proxy = Some(proxy.address());
+    Ok(proxy)
+}
+
+pub fn session_exists(session_id: &str) -> bool {
+    SESSIONS.lock().unwrap().contains_key(session_id)
+}
+
+/// Address of the proxy currently bound to the session
+pub fn session_proxy(session_id: &str) -> Option<String> {
+    SESSIONS.lock().unwrap().get(session_id).and_then(|session| session.proxy.clone())
+}
+
+/// Drop the proxy bound to the session and bind a different one, optionally never giving the old one to this session again
+pub fn rotate_session(session_id: &str, blacklist: bool) -> Result<Proxy> {
+    let now = current_timestamp();
+    let mut sessions = SESSIONS.lock().unwrap();
+    let session = sessions.entry(session_id.to_string()).or_insert_with(|| Session {
+        proxy: None,
+        filter: ProxyFilter::default(),
+        blacklist: vec![],
+        last_active: now,
+    });
+    session.last_active = now;
+    let old_proxy = session.proxy.take();
+    let mut filter = session.filter.clone();
+    filter.excluded = session.blacklist.clone();
+    if let Some(old_proxy) = old_proxy {
+        if blacklist {
+            session.blacklist.push(old_proxy.clone());
+        }
+        filter.excluded.push(old_proxy);
+    }
+
+    let proxy = select_proxy(None, &filter)?;
+    info!("Session {} rotated to {}", session_id, proxy.address());
+    session.proxy = Some(proxy.address());
+    Ok(proxy)
+}
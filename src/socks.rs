@@ -1,47 +1,125 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::io::ErrorKind;
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use fast_socks5::client;
+use fast_socks5::{AuthenticationMethod, client, consts};
 use fast_socks5::client::Socks5Stream;
-use fast_socks5::server::{Config, Socks5Server, Socks5Socket};
+use fast_socks5::server::{Config, Socks5Socket};
+use fast_socks5::util::target_addr::TargetAddr;
 use log::{error, info};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_stream::StreamExt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
 
-use crate::{CONFIG, PROXY_POOL};
+use crate::CONFIG;
 use crate::proxy::{Proxy, ProxyType};
-use crate::time::current_timestamp;
+use crate::proxy_protocol::{is_trusted_source, read_proxy_header};
+use crate::limiter::{acquire_domain_permit, DomainPermit};
+use crate::selector::{ProxyFilter, select_proxy, target_host};
+use crate::session::{select_session_proxy, SessionAuthentication};
+use crate::socks4::{read_socks4_request, reply_socks4, SOCKS4_VERSION};
+use crate::tls::build_tls_acceptor;
+
+#[derive(Debug, Clone)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    Unix(String),
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+pub fn socks_server_config(request_timeout: u64, session_enabled: bool) -> Arc<Config> {
+    let mut server_config: Config = Config::default();
+    server_config.set_request_timeout(request_timeout);
+    // Sessions are identified by the socks username, so clients have to send credentials
+    if session_enabled {
+        server_config.set_authentication(SessionAuthentication);
+    }
+    // Keep domain targets unresolved, they are needed for proxy affinity and resolved by the upstream proxy
+    server_config.set_dns_resolve(false);
+    server_config.set_transfer_data(false);
+    Arc::new(server_config)
+}
 
 pub async fn init_socks_server() -> Result<()> {
     info!("Initializing socks server");
     let global_config = CONFIG.lock().unwrap().deref().clone().unwrap();
-    let mut server_config: Config = Config::default();
-    server_config.set_request_timeout(global_config.socks_server_timeout);
-    server_config.set_dns_resolve(true);
-    server_config.set_transfer_data(false);
-    let listen_addr = format!("127.0.0.1:{}", global_config.socks_server_port);
-    let mut server = Socks5Server::bind(&listen_addr).await?;
-    server.set_config(server_config);
-    let mut incoming = server.incoming();
-    info!("Socks server listening at {}", listen_addr);
-    while let Some(socket_res) = incoming.next().await {
-        match socket_res {
-            Ok(socket) => {
-                let selected_proxy: Proxy;
-                {
-                    let mut proxy_pool = PROXY_POOL.lock().unwrap();
-                    let mut proxy = proxy_pool.pop_first().unwrap();
-                    proxy.last_used = current_timestamp();
-                    proxy_pool.insert(proxy.clone());
-                    selected_proxy = proxy;
-                }
+    for listener in global_config.socks_server_listeners.iter() {
+        let listener = listener.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_socks(listener.port, listener.filter).await {
+                error!("Socks server on port {} failed, {:#}", listener.port, err);
+            }
+        });
+    }
+    serve_socks(global_config.socks_server_port, ProxyFilter::default()).await
+}
 
+/// Accept socks connections on `port`, selecting only proxies matching `filter` for them
+async fn serve_socks(port: u64, filter: ProxyFilter) -> Result<()> {
+    let global_config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let server_config = socks_server_config(global_config.socks_server_timeout, global_config.session_enabled);
+    let tls_acceptor = if global_config.socks_server_tls_enabled {
+        Some(build_tls_acceptor(&global_config).context("Load socks server tls config")?)
+    } else {
+        None
+    };
+    let filter = Arc::new(filter);
+    let bind_ip: IpAddr = global_config.socks_server_bind.parse().context("Parse socks server bind address")?;
+    let listen_addr = SocketAddr::new(bind_ip, port.try_into().context("Socks server port out of range")?);
+    let listener = TcpListener::bind(&listen_addr).await?;
+    info!("Socks server listening at {}", listen_addr);
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, peer_addr)) => {
+                let server_config = Arc::clone(&server_config);
+                let tls_acceptor = tls_acceptor.clone();
+                let filter = Arc::clone(&filter);
+                let trusted_sources = global_config.proxy_protocol_trusted_sources.clone();
+                let proxy_protocol_enabled = global_config.proxy_protocol_enabled;
+                let handshake_timeout = Duration::from_secs(global_config.socks_server_timeout);
                 tokio::spawn(async move {
-                    if let Err(err) = handle_socket(socket, selected_proxy.clone()).await {
-                        error!("Socks server handle error, {:#}", err);
+                    // Connections relayed by a trusted balancer carry the real client address in a PROXY header
+                    let client_addr = ClientAddr::Tcp(if proxy_protocol_enabled && is_trusted_source(&peer_addr.ip(), &trusted_sources) {
+                        // A trusted peer still must not hold the task forever by never sending the header
+                        match timeout(handshake_timeout, read_proxy_header(&mut stream)).await {
+                            Ok(Ok(addr)) => addr.unwrap_or(peer_addr),
+                            Ok(Err(err)) => {
+                                error!("Socks server proxy protocol error from {}, {:#}", peer_addr, err);
+                                return;
+                            }
+                            Err(_) => {
+                                error!("Socks server proxy protocol header from {} timed out", peer_addr);
+                                return;
+                            }
+                        }
+                    } else {
+                        peer_addr
+                    });
+
+                    // Clients may come from untrusted networks, so none may stall the handshake to hold the task
+                    let result = match tls_acceptor {
+                        Some(tls_acceptor) => match timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+                            Ok(Ok(tls_stream)) => handle_connection(tls_stream, server_config, handshake_timeout, &client_addr, &filter).await,
+                            Ok(Err(err)) => Err(anyhow!("Tls handshake failed, {}", err)),
+                            Err(_) => Err(anyhow!("Tls handshake timed out")),
+                        },
+                        None => handle_connection(stream, server_config, handshake_timeout, &client_addr, &filter).await,
+                    };
+                    if let Err(err) = result {
+                        error!("Socks server handle error for {}, {:#}", client_addr, err);
                     }
                 });
             }
@@ -50,10 +128,29 @@ pub async fn init_socks_server() -> Result<()> {
             }
         }
     }
-    Ok(())
 }
 
-async fn handle_socket<T>(socket: Socks5Socket<T>, proxy: Proxy) -> Result<()>
+/// Dispatch an inbound connection to the SOCKS5 or SOCKS4 handler by its version byte,
+/// which has to arrive within `handshake_timeout`
+pub async fn handle_connection<T>(stream: T, server_config: Arc<Config>, handshake_timeout: Duration, client_addr: &ClientAddr, filter: &ProxyFilter) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+{
+    // Peek the version byte through the buffer, the handlers still read the whole handshake
+    let mut stream = BufReader::new(stream);
+    let version = *timeout(handshake_timeout, stream.fill_buf())
+        .await
+        .context("Timed out waiting for handshake")??
+        .first()
+        .context("Connection closed before handshake")?;
+    match version {
+        consts::SOCKS5_VERSION => handle_socket(Socks5Socket::new(stream, server_config), client_addr, filter).await,
+        SOCKS4_VERSION => handle_socks4(stream, handshake_timeout, client_addr, filter).await,
+        _ => Err(anyhow!("Unsupported socks version {}", version)),
+    }
+}
+
+async fn handle_socket<T>(socket: Socks5Socket<T>, client_addr: &ClientAddr, filter: &ProxyFilter) -> Result<()>
     where
         T: AsyncRead + AsyncWrite + Unpin,
 {
@@ -63,92 +160,136 @@ async fn handle_socket<T>(socket: Socks5Socket<T>, proxy: Proxy) -> Result<()>
         .await
         .context("Upgrade incoming socket to socks5")?;
 
-    // get resolved target addr
-    socks5_socket
-        .resolve_dns()
-        .await
-        .context("Resolve target dns for incoming socket")?;
-    let socket_addr = socks5_socket
+    let target = socks5_socket
         .target_addr()
         .context("Find target address for incoming socket")?
-        .to_socket_addrs()
-        .context("Convert target address of incoming socket to socket addresses")?
-        .next()
-        .context("Reach out to target of incoming socket")?;
+        .clone();
+    let username = match socks5_socket.auth() {
+        AuthenticationMethod::Password { username, .. } => Some(username.clone()),
+        _ => None,
+    };
+
+    relay(&mut socks5_socket, client_addr, username.as_deref(), filter, &target).await
+}
+
+async fn handle_socks4<T>(mut stream: T, handshake_timeout: Duration, client_addr: &ClientAddr, filter: &ProxyFilter) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+{
+    // SOCKS5 requests are bounded by the request timeout of the server config, this does the same for SOCKS4
+    let request = timeout(handshake_timeout, read_socks4_request(&mut stream))
+        .await
+        .context("Timed out reading socks4 request")?
+        .context("Read socks4 request of incoming socket")?;
+    let userid = Some(request.userid.as_str()).filter(|userid| !userid.is_empty());
 
+    // The client is only told the request is granted once the upstream tunnel stands
+    let (downstream, _permit) = match connect_upstream(client_addr, userid, filter, &request.target).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            reply_socks4(&mut stream, false).await?;
+            return Err(err);
+        }
+    };
+    reply_socks4(&mut stream, true).await?;
+    transfer(&mut stream, downstream).await
+}
+
+/// Apply the domain limits, then tunnel the inbound stream to the target through a proxy selected from the pool
+pub async fn relay<T>(inbound: &mut T, client_addr: &ClientAddr, username: Option<&str>, filter: &ProxyFilter, target: &TargetAddr) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (downstream, _permit) = connect_upstream(client_addr, username, filter, target).await?;
+    transfer(inbound, downstream).await
+}
+
+/// Apply the domain limits and connect to the target through a proxy selected from the pool.
+/// The returned permit has to be held for as long as the tunnel is used.
+async fn connect_upstream(client_addr: &ClientAddr, username: Option<&str>, filter: &ProxyFilter, target: &TargetAddr) -> Result<(TcpStream, DomainPermit)> {
+    let permit = acquire_domain_permit(&target_host(target)).await?;
+    let session_enabled = CONFIG.lock().unwrap().as_ref().unwrap().session_enabled;
+    let proxy = match username {
+        Some(session_id) if session_enabled => select_session_proxy(session_id, target, filter)?,
+        _ => select_proxy(Some(target), filter)?,
+    };
+    match username {
+        Some(username) => info!("Client {} ({}) connecting to {} through {}:{}", client_addr, username, target, proxy.proxy_ip, proxy.proxy_port),
+        None => info!("Client {} connecting to {} through {}:{}", client_addr, target, proxy.proxy_ip, proxy.proxy_port),
+    }
+    let downstream = tunnel(&proxy, target).await?;
+    Ok((downstream, permit))
+}
+
+/// Connect to the target through the given pool proxy, returns the stream to the target once the proxy granted it
+pub async fn tunnel(proxy: &Proxy, target: &TargetAddr) -> Result<TcpStream> {
     match proxy.proxy_type {
         ProxyType::SOCKS5 => {
-            let mut downstream = Socks5Stream::connect(
-                format!("{}:{}", proxy.proxy_ip, proxy.proxy_port.to_string()),
-                socket_addr.ip().to_string(),
-                socket_addr.port(),
+            let (target_host, target_port) = match target {
+                TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                TargetAddr::Domain(domain, port) => (domain.clone(), *port),
+            };
+            let downstream = Socks5Stream::connect(
+                proxy.address(),
+                target_host,
+                target_port,
                 client::Config::default(),
             )
                 .await
-                .context("Connect to downstream socks5 proxy for incoming socket")?;
-            match tokio::io::copy_bidirectional(&mut downstream, &mut socks5_socket).await {
-                Ok(_) => {
-                    Ok(())
-                }
-                Err(err) => match err.kind() {
-                    ErrorKind::NotConnected => {
-                        Ok(())
-                    }
-                    ErrorKind::ConnectionReset => {
-                        Ok(())
-                    }
-                    _ => Err(anyhow!(
-                            "Socket transfer error, {:#}",
-                            err
-                        ))
-                },
-            }
+                .context("Connect to downstream socks5 proxy for inbound connection")?;
+            Ok(downstream.get_socket())
         }
         ProxyType::HTTP => {
-            let mut downstream = TcpStream::connect(format!("{}:{}", proxy.proxy_ip, proxy.proxy_port))
+            let mut downstream = TcpStream::connect(proxy.address())
                 .await
-                .context("Connect to downstream http proxy for incoming socket")?;
-            let connect_request = String::from(
-                format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
-                        socks5_socket.target_addr().unwrap(),
-                        socks5_socket.target_addr().unwrap(),
-                )
+                .context("Connect to downstream http proxy for inbound connection")?;
+            let connect_request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
+                                          target,
+                                          target,
             );
-            downstream.write_all(connect_request.as_bytes()).await.unwrap();
+            downstream.write_all(connect_request.as_bytes()).await?;
             // Read from downstream until \r\n\r\n appears, indicating http header finished
             let mut response: Vec<u8> = vec![];
             loop {
-                let buf = downstream.read_u8().await.unwrap();
+                let buf = downstream.read_u8().await?;
                 response.push(buf);
                 if response.ends_with(&[0x0d,0x0a,0x0d,0x0a]) {
                     break;
                 }
             }
-            let response_str = String::from_utf8(response).unwrap();
+            let response_str = String::from_utf8_lossy(&response);
             if !response_str.contains("200") {
                 Err(anyhow!("downstream http proxy connect failed"))
             } else {
-                match tokio::io::copy_bidirectional(&mut downstream, &mut socks5_socket).await {
-                    Ok(_) => {
-                        Ok(())
-                    }
-                    Err(err) => match err.kind() {
-                        ErrorKind::NotConnected => {
-                            Ok(())
-                        }
-                        ErrorKind::ConnectionReset => {
-                            Ok(())
-                        }
-                        _ => Err(anyhow!(
-                            "Socket transfer error, {:#}",
-                            err
-                        ))
-                    },
-                }
+                Ok(downstream)
             }
         }
         _ => {
             Err(anyhow!("Unsupported protocol"))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Relay data between the inbound stream and the tunnel to the target until either side closes
+async fn transfer<T>(inbound: &mut T, mut downstream: TcpStream) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+{
+    match tokio::io::copy_bidirectional(&mut downstream, inbound).await {
+        Ok(_) => {
+            Ok(())
+        }
+        Err(err) => match err.kind() {
+            ErrorKind::NotConnected => {
+                Ok(())
+            }
+            ErrorKind::ConnectionReset => {
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                    "Socket transfer error, {:#}",
+                    err
+                ))
+        },
+    }
+}
@@ -0,0 +1,135 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{anyhow, Context, Result};
+use fast_socks5::util::target_addr::TargetAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+const SOCKS4_REPLY_GRANTED: u8 = 0x5a;
+const SOCKS4_REPLY_REJECTED: u8 = 0x5b;
+// Usernames and domains longer than this are refused instead of buffered forever
+const SOCKS4_MAX_FIELD_LENGTH: usize = 255;
+
+pub struct Socks4Request {
+    pub target: TargetAddr,
+    pub userid: String,
+}
+
+/// Read a SOCKS4 or SOCKS4a CONNECT request, replying with a rejection to anything else.
+/// The caller answers a valid request with `reply_socks4`.
+pub async fn read_socks4_request<T>(stream: &mut T) -> Result<Socks4Request>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != SOCKS4_VERSION {
+        return Err(anyhow!("Unsupported socks version {}", version));
+    }
+    let command = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    let mut ip = [0u8; 4];
+    stream.read_exact(&mut ip).await?;
+    let userid = read_null_terminated(stream).await.context("Read socks4 userid")?;
+    if command != SOCKS4_CMD_CONNECT {
+        reply_socks4(stream, false).await?;
+        return Err(anyhow!("Unsupported socks4 command {}", command));
+    }
+
+    // SOCKS4a marks a domain target with the address 0.0.0.x, x non zero
+    let target = if ip[0] == 0 && ip[1] == 0 && ip[2] == 0 && ip[3] != 0 {
+        let domain = read_null_terminated(stream).await.context("Read socks4a domain")?;
+        TargetAddr::Domain(domain, port)
+    } else {
+        TargetAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+    };
+    Ok(Socks4Request { target, userid })
+}
+
+pub async fn reply_socks4<T>(stream: &mut T, granted: bool) -> Result<()>
+    where
+        T: AsyncWrite + Unpin,
+{
+    let status = if granted { SOCKS4_REPLY_GRANTED } else { SOCKS4_REPLY_REJECTED };
+    stream.write_all(&[0x00, status, 0, 0, 0, 0, 0, 0]).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_null_terminated<T>(stream: &mut T) -> Result<String>
+    where
+        T: AsyncRead + Unpin,
+{
+    let mut field: Vec<u8> = vec![];
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0x00 {
+            break;
+        }
+        if field.len() >= SOCKS4_MAX_FIELD_LENGTH {
+            return Err(anyhow!("Socks4 request field too long"));
+        }
+        field.push(byte);
+    }
+    Ok(String::from_utf8(field)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    async fn read_request(request: &[u8]) -> (Result<Socks4Request>, Vec<u8>) {
+        let mut stream = Cursor::new(request.to_vec());
+        let result = read_socks4_request(&mut stream).await;
+        // Replies are written after the request in the cursor buffer
+        let reply = stream.into_inner().split_off(request.len());
+        (result, reply)
+    }
+
+    #[tokio::test]
+    async fn reads_socks4_and_socks4a_requests() {
+        let cases: [(&[u8], TargetAddr, &str); 3] = [
+            (b"\x04\x01\x00\x50\xc0\x00\x02\x01alice\x00", TargetAddr::Ip("192.0.2.1:80".parse().unwrap()), "alice"),
+            (b"\x04\x01\x01\xbb\xc0\x00\x02\x01\x00", TargetAddr::Ip("192.0.2.1:443".parse().unwrap()), ""),
+            (b"\x04\x01\x01\xbb\x00\x00\x00\x01bob\x00example.com\x00", TargetAddr::Domain(String::from("example.com"), 443), "bob"),
+        ];
+        for (request, target, userid) in cases {
+            let (result, reply) = read_request(request).await;
+            let result = result.unwrap();
+            assert_eq!(result.target.to_string(), target.to_string(), "{:02x?}", request);
+            assert_eq!(result.userid, userid);
+            assert!(reply.is_empty(), "valid requests are answered by the caller");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_requests() {
+        // BIND is refused with a rejection reply
+        let (result, reply) = read_request(b"\x04\x02\x00\x50\xc0\x00\x02\x01\x00").await;
+        assert!(result.is_err());
+        assert_eq!(reply, [0x00, SOCKS4_REPLY_REJECTED, 0, 0, 0, 0, 0, 0]);
+
+        let cases: [&[u8]; 3] = [
+            b"\x05\x01\x00\x50\xc0\x00\x02\x01\x00",
+            // SOCKS4a domain missing its terminator
+            b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com",
+            // Truncated address
+            b"\x04\x01\x00\x50\xc0\x00",
+        ];
+        for request in cases {
+            let (result, _) = read_request(request).await;
+            assert!(result.is_err(), "{:02x?}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_userid() {
+        let mut request = b"\x04\x01\x00\x50\xc0\x00\x02\x01".to_vec();
+        request.extend(std::iter::repeat_n(b'a', SOCKS4_MAX_FIELD_LENGTH + 1));
+        request.push(0x00);
+        let (result, _) = read_request(&request).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Config;
+
+/// Build the acceptor wrapping inbound socks connections in TLS.
+/// Client certificates are required and verified against `socks_server_tls_client_ca` when it is set.
+pub fn build_tls_acceptor(config: &Config) -> Result<TlsAcceptor> {
+    let certs = load_certs(&config.socks_server_tls_cert)?;
+    let key = load_private_key(&config.socks_server_tls_key)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let server_config = match &config.socks_server_tls_client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(&cert).context("Add client ca certificate")?;
+            }
+            builder
+                .with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+                .with_single_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?
+    };
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("Open certificate file {}", path))?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("Open private key file {}", path))?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(anyhow!("No private key found in {}", path))
+}
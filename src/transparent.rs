@@ -0,0 +1,115 @@
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+
+use anyhow::{anyhow, Context, Result};
+use fast_socks5::util::target_addr::TargetAddr;
+use log::{error, info};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::CONFIG;
+use crate::config::TransparentMode;
+use crate::selector::ProxyFilter;
+use crate::socks::{ClientAddr, relay};
+
+// From linux/netfilter_ipv4.h
+const SO_ORIGINAL_DST: libc::c_int = 80;
+
+/// Listen for connections redirected by the firewall and tunnel them to their original destination through the pool.
+/// Traffic generated by Akivili itself must be excluded from the redirect rules, e.g. with an owner match.
+pub async fn init_transparent_server() -> Result<()> {
+    info!("Initializing transparent proxy server");
+    let global_config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let mode = global_config.transparent_proxy_mode;
+    // SO_ORIGINAL_DST is only read for IPv4 sockets
+    let bind_ip: Ipv4Addr = global_config.transparent_proxy_bind.parse().context("Parse transparent proxy bind address")?;
+    let listen_port: u16 = global_config.transparent_proxy_port.try_into().context("Transparent proxy port out of range")?;
+    let listen_addr = SocketAddr::new(IpAddr::V4(bind_ip), listen_port);
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    if mode == TransparentMode::Tproxy {
+        set_ip_transparent(&socket)?;
+    }
+    socket.bind(listen_addr)?;
+    let listener: TcpListener = socket.listen(1024)?;
+    info!("Transparent proxy server listening at {} in {:?} mode", listen_addr, mode);
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, client_addr)) => {
+                tokio::spawn(async move {
+                    let target = match original_destination(&stream, mode) {
+                        Ok(target) => target,
+                        Err(err) => {
+                            error!("Transparent proxy server original destination error for {}, {:#}", client_addr, err);
+                            return;
+                        }
+                    };
+                    // A connection made straight to the listener would otherwise loop back into itself
+                    if is_direct_connection(&stream, &target, mode, listen_port) {
+                        error!("Transparent proxy server refused direct connection from {}", client_addr);
+                        return;
+                    }
+                    if let Err(err) = relay(&mut stream, &ClientAddr::Tcp(client_addr), None, &ProxyFilter::default(), &TargetAddr::Ip(target)).await {
+                        error!("Transparent proxy server handle error for {}, {:#}", client_addr, err);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("Transparent proxy server accept error, {:?}", err);
+            }
+        }
+    }
+}
+
+fn original_destination(stream: &TcpStream, mode: TransparentMode) -> Result<SocketAddr> {
+    match mode {
+        // TPROXY keeps the original destination as the local address of the accepted socket
+        TransparentMode::Tproxy => Ok(stream.local_addr()?),
+        TransparentMode::Redirect => {
+            let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    stream.as_raw_fd(),
+                    libc::SOL_IP,
+                    SO_ORIGINAL_DST,
+                    &mut addr as *mut libc::sockaddr_in as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                return Err(anyhow!("Get SO_ORIGINAL_DST failed, {}", std::io::Error::last_os_error()));
+            }
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+        }
+    }
+}
+
+/// Whether the connection reached the listener without being redirected by the firewall
+fn is_direct_connection(stream: &TcpStream, target: &SocketAddr, mode: TransparentMode, listen_port: u16) -> bool {
+    match mode {
+        // TPROXY keeps the destination as the local address, only the listener port tells a direct connection apart
+        TransparentMode::Tproxy => target.port() == listen_port,
+        // REDIRECT rewrites the destination to the listener, an original destination equal to it was never rewritten
+        TransparentMode::Redirect => stream.local_addr().map_or(true, |local_addr| local_addr == *target),
+    }
+}
+
+fn set_ip_transparent(socket: &TcpSocket) -> Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IP,
+            libc::IP_TRANSPARENT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!("Set IP_TRANSPARENT failed (CAP_NET_ADMIN required), {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::fs;
+use std::fs::{DirBuilder, Permissions};
+use std::ops::Deref;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{error, info};
+use tokio::net::UnixListener;
+
+use crate::CONFIG;
+use crate::selector::ProxyFilter;
+use crate::socks::{ClientAddr, handle_connection, socks_server_config};
+
+/// Serve the socks server on a unix domain socket, so access can be limited with file permissions
+pub async fn init_unix_socks_server() -> Result<()> {
+    info!("Initializing unix socks server");
+    let global_config = CONFIG.lock().unwrap().deref().clone().unwrap();
+    let server_config = socks_server_config(global_config.socks_server_timeout, global_config.session_enabled);
+    let handshake_timeout = Duration::from_secs(global_config.socks_server_timeout);
+    let socket_path = Path::new(&global_config.unix_socket_path);
+    // Clean up the socket file left behind by a previous run
+    if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", socket_path.display()));
+        }
+        fs::remove_file(socket_path)?;
+    }
+    let mode = u32::from_str_radix(&global_config.unix_socket_mode, 8).context("Parse unix socket mode")?;
+    // Bind inside a directory only we can enter, so nobody can connect before mode and owner are applied,
+    // then move the finished socket into place
+    let file_name = socket_path.file_name().context("Unix socket path has no file name")?;
+    let private_dir = socket_path
+        .with_file_name(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private_dir).context("Create private directory for unix socket")?;
+    let private_path = private_dir.join(file_name);
+    let bound = bind_private(&private_path, mode, global_config.unix_socket_uid, global_config.unix_socket_gid)
+        .and_then(|listener| {
+            fs::rename(&private_path, socket_path).context("Move unix socket into place")?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    let listener = bound?;
+    info!("Unix socks server listening at {}", socket_path.display());
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let server_config = Arc::clone(&server_config);
+                let client_addr = ClientAddr::Unix(global_config.unix_socket_path.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, server_config, handshake_timeout, &client_addr, &ProxyFilter::default()).await {
+                        error!("Unix socks server handle error for {}, {:#}", client_addr, err);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("Unix socks server accept error, {:?}", err);
+            }
+        }
+    }
+}
+
+fn bind_private(path: &Path, mode: u32, uid: Option<u32>, gid: Option<u32>) -> Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode)).context("Set unix socket permissions")?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid).context("Set unix socket owner")?;
+    }
+    Ok(listener)
+}